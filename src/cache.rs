@@ -1,7 +1,75 @@
-use std::path::PathBuf;
+use std::{io, fmt, fs};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use image;
+use image::ImageOutputFormat;
+use serde_json;
+
+use comic::COMIC_DIR;
+use converter::source_format;
 
 const READER_CHECKER_FILE: &str = "META-INF/container.xml";
 
+/// Sub-directory of the cache dir holding cover thumbnails
+const THUMBNAIL_DIR: &str = "thumbnails";
+
+const THUMBNAIL_QUALITY: u8 = 85;
+
+/// File in each cached book recording what it was made from
+const SOURCE_FILE: &str = ".source.json";
+
+/// Sub-directory of the cache dir holding the log of the latest conversion
+/// of each book
+const LOG_DIR: &str = "logs";
+
+/// Numbers staging files and dirs made by this process
+static STAGING_SEQ: AtomicUsize = AtomicUsize::new(0);
+
+/// Hidden path next to `path` to make it at before moving it into place,
+/// e.g. ".42.1234-0.partial" for "42". The name is unique to the process and
+/// the call, so that concurrent writers of the same path, including other
+/// processes sharing the cache dir, never write to each other's files.
+pub fn staging_path(path: &Path, ext: &str) -> io::Result<PathBuf> {
+    match path.file_name() {
+        Some(name) => Ok(path.with_file_name(format!(
+            ".{}.{}-{}.{}", name.to_string_lossy(), process::id(),
+            STAGING_SEQ.fetch_add(1, AtomicOrdering::SeqCst), ext))),
+        None => Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                   format!("Invalid cache path {:?}", path)))
+    }
+}
+
+/// Writes `path` via a staging file renamed into place once complete, so
+/// that concurrent readers never see a partially written file.
+pub fn write_atomically<F, E>(path: &Path, write: F) -> Result<(), E>
+    where F: FnOnce(&mut BufWriter<File>) -> Result<(), E>, E: From<io::Error> {
+    let tmp_path = try!(staging_path(path, "tmp"));
+    let result = File::create(&tmp_path).map_err(E::from).and_then(|file| {
+        let mut out = BufWriter::new(file);
+        try!(write(&mut out));
+        out.flush().map_err(E::from)
+    }).and_then(|_| fs::rename(&tmp_path, path).map_err(E::from));
+    if result.is_err() {
+        if let Err(e) = fs::remove_file(&tmp_path) {
+            if e.kind() != io::ErrorKind::NotFound {
+                warn!("Failed to remove {:?}: {}", tmp_path, e);
+            }
+        }
+    }
+    result
+}
+
+/// File in each cached book touched on access; its mtime drives LRU eviction
+const ACCESS_STAMP_FILE: &str = ".last-access";
+
 pub fn check_cache_availability(reader_path: &PathBuf) -> bool {
     let mut checker_path = reader_path.clone();
     checker_path.push(READER_CHECKER_FILE);
@@ -9,4 +77,540 @@ pub fn check_cache_availability(reader_path: &PathBuf) -> bool {
     checker_path.is_file()
 }
 
+/// Identifies the version of the source file a cached book was made from.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SourceFingerprint {
+    pub path: PathBuf,
+    pub size: u64,
+    /// Modification time of the file in seconds since the epoch
+    pub mtime: u64,
+    /// `books.last_modified` in the metadata DB
+    pub last_modified: String,
+}
+
+impl SourceFingerprint {
+    pub fn of(path: &Path, last_modified: &str) -> io::Result<Self> {
+        let meta = try!(fs::metadata(path));
+        let mtime = try!(meta.modified()).duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs()).unwrap_or(0);
+        Ok(SourceFingerprint {
+            path: path.to_path_buf(),
+            size: meta.len(),
+            mtime: mtime,
+            last_modified: last_modified.to_string(),
+        })
+    }
+
+    /// Calibre format name of the source, e.g. "EPUB".
+    pub fn format(&self) -> String {
+        source_format(&self.path)
+    }
+
+    /// Records the fingerprint in the extracted book at `reader_path`.
+    /// The file is replaced atomically so that concurrent readers never see
+    /// a truncated one.
+    pub fn write_to(&self, reader_path: &Path) -> io::Result<()> {
+        let mut path = reader_path.to_path_buf();
+        path.push(SOURCE_FILE);
+        write_atomically(&path, |out| {
+            serde_json::to_writer(out, self)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+        })
+    }
+
+    pub fn read_from(reader_path: &Path) -> Option<Self> {
+        let mut path = reader_path.to_path_buf();
+        path.push(SOURCE_FILE);
+        File::open(path).ok()
+            .and_then(|file| serde_json::from_reader(file).ok())
+    }
+}
+
+/// Checks that the book is available in the cache and was made from the
+/// current version of one of its candidate sources, returning the one used.
+pub fn check_cache_freshness(reader_path: &PathBuf, sources: &[SourceFingerprint])
+                             -> Option<SourceFingerprint> {
+    if !check_cache_availability(reader_path) {
+        return None;
+    }
+    match SourceFingerprint::read_from(reader_path) {
+        Some(ref cached) if sources.contains(cached) => Some(cached.clone()),
+        cached => {
+            debug!("Cached copy in {:?} is stale: {:?} not in {:?}",
+                   reader_path, cached, sources);
+            None
+        }
+    }
+}
+
+/// Parses sizes like "500M", "500MB" or "2G" into bytes.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    // The trailing "B" of e.g. "500MB" is optional.
+    let num = match s.char_indices().last() {
+        Some((i, c)) if c.eq_ignore_ascii_case(&'B') => &s[..i],
+        _ => s
+    };
+    let (num, unit) = match num.char_indices().last() {
+        Some((i, c)) if c.is_alphabetic() => (&num[..i], c.to_ascii_uppercase()),
+        _ => (num, 'B')
+    };
+    let multiplier: u64 = match unit {
+        'B' => 1,
+        'K' => 1 << 10,
+        'M' => 1 << 20,
+        'G' => 1 << 30,
+        'T' => 1 << 40,
+        _ => return Err(format!("Unknown size unit in {}", s))
+    };
+    let n = try!(num.trim().parse::<u64>().map_err(|_| format!("Invalid size: {}", s)));
+    n.checked_mul(multiplier).ok_or_else(|| format!("Size too large: {}", s))
+}
+
+/// Total size of the files in `path`. Files removed while it's being
+/// measured, e.g. by a concurrent eviction, count as nothing.
+pub fn dir_size(path: &Path) -> io::Result<u64> {
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e)
+    };
+    let mut size = 0;
+    for entry in entries {
+        let entry = try!(entry);
+        let meta = match fs::symlink_metadata(entry.path()) {
+            Ok(meta) => meta,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e)
+        };
+        size += if meta.is_dir() {
+            try!(dir_size(&entry.path()))
+        } else {
+            meta.len()
+        };
+    }
+    Ok(size)
+}
+
+/// A book extracted in the cache dir, or the extracted pages of a comic.
+pub struct CacheEntry {
+    pub bookid: i64,
+    pub path: PathBuf,
+    pub size: u64,
+    pub last_access: SystemTime,
+    /// Whether the entry holds the pages of a comic
+    pub comic: bool,
+}
+
+pub struct GcReport {
+    pub removed: Vec<i64>,
+    pub freed: u64,
+    pub remaining: u64,
+}
+
+fn touch_dir(path: &Path) {
+    if !path.is_dir() {
+        return;
+    }
+    let mut stamp_path = path.to_path_buf();
+    stamp_path.push(ACCESS_STAMP_FILE);
+    if let Err(e) = File::create(&stamp_path) {
+        warn!("Failed to update {:?}: {}", stamp_path, e);
+    }
+}
+
+/// Collects the numerically named directories in `dir`.
+fn scan_entries(dir: &Path, comic: bool, entries: &mut Vec<CacheEntry>)
+                -> io::Result<()> {
+    for entry in try!(fs::read_dir(dir)) {
+        let entry = try!(entry);
+        // Staging directories, thumbnails and logs don't have numeric names.
+        let bookid = match entry.file_name().to_str().and_then(|s| s.parse().ok()) {
+            Some(id) => id,
+            None => continue
+        };
+        let path = entry.path();
+        if !path.is_dir() {
+            continue;
+        }
+        let mut stamp_path = path.clone();
+        stamp_path.push(ACCESS_STAMP_FILE);
+        let last_access = match fs::metadata(&stamp_path)
+            .or_else(|_| fs::metadata(&path))
+            .and_then(|m| m.modified()) {
+            Ok(time) => time,
+            // Evicted or replaced meanwhile
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e)
+        };
+        entries.push(CacheEntry {
+            bookid: bookid,
+            size: try!(dir_size(&path)),
+            path: path,
+            last_access: last_access,
+            comic: comic,
+        });
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+struct LockTable {
+    /// Number of shared holders of each locked entry, or -1 if it's locked
+    /// exclusively
+    holders: Mutex<HashMap<i64, isize>>,
+    released: Condvar,
+}
+
+impl LockTable {
+    /// Entries are only counted, so the table is still usable after a panic
+    /// while it was locked.
+    fn holders(&self) -> MutexGuard<HashMap<i64, isize>> {
+        self.holders.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Readers-writer locks of cache entries keyed by book ID. Unlike with
+/// `RwLock`, the guards own a handle to the lock table, so there's no need
+/// to keep a lock of each book around.
+#[derive(Clone, Default)]
+pub struct EntryLocks {
+    table: Arc<LockTable>,
+}
+
+/// Lock of a cache entry, released on drop
+pub struct EntryGuard {
+    table: Arc<LockTable>,
+    bookid: i64,
+    exclusive: bool,
+}
+
+impl EntryLocks {
+    /// Locks the entry shared, waiting while it's locked exclusively.
+    pub fn read(&self, bookid: i64) -> EntryGuard {
+        let mut holders = self.table.holders();
+        while holders.get(&bookid).map_or(false, |&n| n < 0) {
+            holders = self.table.released.wait(holders)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        *holders.entry(bookid).or_insert(0) += 1;
+        EntryGuard { table: self.table.clone(), bookid: bookid, exclusive: false }
+    }
+
+    /// Locks the entry exclusively, waiting while it's locked at all.
+    pub fn write(&self, bookid: i64) -> EntryGuard {
+        let mut holders = self.table.holders();
+        while holders.contains_key(&bookid) {
+            holders = self.table.released.wait(holders)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        holders.insert(bookid, -1);
+        EntryGuard { table: self.table.clone(), bookid: bookid, exclusive: true }
+    }
 
+    /// Locks the entry exclusively unless it's in use.
+    pub fn try_write(&self, bookid: i64) -> Option<EntryGuard> {
+        let mut holders = self.table.holders();
+        if holders.contains_key(&bookid) {
+            return None;
+        }
+        holders.insert(bookid, -1);
+        Some(EntryGuard { table: self.table.clone(), bookid: bookid, exclusive: true })
+    }
+}
+
+impl EntryGuard {
+    /// Turns an exclusive lock into a shared one without letting another
+    /// writer in between.
+    pub fn downgrade(mut self) -> EntryGuard {
+        if self.exclusive {
+            self.table.holders().insert(self.bookid, 1);
+            self.exclusive = false;
+            self.table.released.notify_all();
+        }
+        self
+    }
+}
+
+impl Drop for EntryGuard {
+    fn drop(&mut self) {
+        let mut holders = self.table.holders();
+        let remaining = if self.exclusive {
+            0
+        } else {
+            holders.get(&self.bookid).map_or(0, |&n| n - 1)
+        };
+        if remaining > 0 {
+            holders.insert(self.bookid, remaining);
+        } else {
+            holders.remove(&self.bookid);
+        }
+        self.table.released.notify_all();
+    }
+}
+
+/// Keeps the extracted books in the cache dir within a size budget by
+/// evicting the least recently accessed ones.
+#[derive(Clone)]
+pub struct CacheManager {
+    path: PathBuf,
+    limit: Option<u64>,
+    gc_lock: Arc<Mutex<()>>,
+    comic_locks: EntryLocks,
+}
+
+impl CacheManager {
+    pub fn new(path: PathBuf, limit: Option<u64>) -> Self {
+        CacheManager {
+            path: path,
+            limit: limit,
+            gc_lock: Arc::new(Mutex::new(())),
+            comic_locks: EntryLocks::default(),
+        }
+    }
+
+    /// Path of the extracted pages of the comic
+    pub fn comic_path(&self, bookid: i64) -> PathBuf {
+        let mut path = self.path.clone();
+        path.push(COMIC_DIR);
+        path.push(format!("{}", bookid));
+        path
+    }
+
+    /// Locks of the extracted pages of each comic. Pages are only discarded
+    /// under the exclusive lock, and eviction skips comics in use.
+    pub fn comic_locks(&self) -> &EntryLocks {
+        &self.comic_locks
+    }
+
+    pub fn limit(&self) -> Option<u64> {
+        self.limit
+    }
+
+    /// Total size of the extracted books and comics, and of the thumbnails
+    /// and logs
+    pub fn usage(&self) -> io::Result<u64> {
+        let entries = try!(self.entries());
+        let aux_size = try!(self.aux_size());
+        Ok(entries.iter().map(|e| e.size).sum::<u64>() + aux_size)
+    }
+
+    /// Size of the thumbnails and logs. They're small and never evicted, but
+    /// count towards the limit so that the cache dir stays within it.
+    fn aux_size(&self) -> io::Result<u64> {
+        let mut size = 0;
+        for name in &[THUMBNAIL_DIR, LOG_DIR] {
+            let mut path = self.path.clone();
+            path.push(name);
+            size += try!(dir_size(&path));
+        }
+        Ok(size)
+    }
+
+    /// Path of the log of the latest conversion of the book
+    pub fn log_path(&self, bookid: i64) -> PathBuf {
+        let mut path = self.path.clone();
+        path.push(LOG_DIR);
+        path.push(format!("{}.log", bookid));
+        path
+    }
+
+    /// Records an access to the cached copy of the book.
+    pub fn touch(&self, bookid: i64) {
+        let mut path = self.path.clone();
+        path.push(format!("{}", bookid));
+        touch_dir(&path);
+    }
+
+    /// Records an access to the extracted pages of the comic.
+    pub fn touch_comic(&self, bookid: i64) {
+        touch_dir(&self.comic_path(bookid));
+    }
+
+    /// Lists extracted books and comics, least recently accessed first.
+    pub fn entries(&self) -> io::Result<Vec<CacheEntry>> {
+        let mut entries = Vec::new();
+        try!(scan_entries(&self.path, false, &mut entries));
+        let mut comic_path = self.path.clone();
+        comic_path.push(COMIC_DIR);
+        if comic_path.is_dir() {
+            try!(scan_entries(&comic_path, true, &mut entries));
+        }
+        entries.sort_by_key(|e| e.last_access);
+        Ok(entries)
+    }
+
+    /// Removes the least recently accessed books until the total size of
+    /// the cache fits in the limit. The entry at `keep`, e.g. a book that
+    /// was just converted, is never removed even if it alone exceeds the
+    /// limit.
+    pub fn collect_garbage(&self, dry_run: bool, keep: Option<&Path>)
+                           -> io::Result<GcReport> {
+        // The lock guards no data, so it's still usable after a panic in a
+        // previous collection.
+        let _guard = self.gc_lock.lock().unwrap_or_else(PoisonError::into_inner);
+        let entries = try!(self.entries());
+        let mut remaining: u64 =
+            entries.iter().map(|e| e.size).sum::<u64>() + try!(self.aux_size());
+        let mut report = GcReport {
+            removed: Vec::new(),
+            freed: 0,
+            remaining: remaining,
+        };
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => return Ok(report)
+        };
+
+        for entry in entries {
+            if remaining <= limit {
+                break;
+            }
+            if keep == Some(entry.path.as_path()) {
+                continue;
+            }
+            // Pages of a comic being read would vanish under the reader.
+            let _guard = if entry.comic {
+                match self.comic_locks.try_write(entry.bookid) {
+                    Some(guard) => Some(guard),
+                    None => {
+                        debug!("Not evicting comic {}; it's in use", entry.bookid);
+                        continue;
+                    }
+                }
+            } else {
+                None
+            };
+            info!("Evicting book {} ({} bytes) from the cache", entry.bookid, entry.size);
+            if !dry_run {
+                match fs::remove_dir_all(&entry.path) {
+                    Ok(()) => {},
+                    // Removed by someone else since the scan
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+                    Err(e) => return Err(e)
+                }
+            }
+            remaining -= entry.size;
+            report.freed += entry.size;
+            report.removed.push(entry.bookid);
+        }
+        report.remaining = remaining;
+        Ok(report)
+    }
+}
+
+#[derive(Debug)]
+pub enum ThumbnailError {
+    IoError(io::Error),
+    ImageError(image::ImageError),
+}
+
+impl Error for ThumbnailError {
+    fn description(&self) -> &str {
+        "thumbnail error"
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match self {
+            ThumbnailError::IoError(e) => Some(e),
+            ThumbnailError::ImageError(e) => Some(e),
+        }
+    }
+}
+
+impl fmt::Display for ThumbnailError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ThumbnailError::IoError(e) =>
+                write!(f, "Failed to write thumbnail: {}", e),
+            ThumbnailError::ImageError(e) =>
+                write!(f, "Failed to resize cover: {}", e),
+        }
+    }
+}
+
+impl From<io::Error> for ThumbnailError {
+    fn from(e: io::Error) -> Self {
+        ThumbnailError::IoError(e)
+    }
+}
+
+impl From<image::ImageError> for ThumbnailError {
+    fn from(e: image::ImageError) -> Self {
+        ThumbnailError::ImageError(e)
+    }
+}
+
+/// Returns the path of a thumbnail of `cover_path` fitting in a `size`x`size`
+/// box, generating it if necessary.
+///
+/// Thumbnails are keyed by `version` (typically `books.last_modified`) so that
+/// an updated cover is picked up; thumbnails of older versions are removed.
+pub fn get_thumbnail(cache_path: &Path, cover_path: &Path,
+                     bookid: i64, size: u32, version: &str)
+                     -> Result<PathBuf, ThumbnailError> {
+    let version: String = version.chars().filter(|c| c.is_ascii_digit()).collect();
+    let prefix = format!("{}-{}-", bookid, size);
+
+    let mut thumbnail_dir = cache_path.to_path_buf();
+    thumbnail_dir.push(THUMBNAIL_DIR);
+    let mut thumbnail_path = thumbnail_dir.clone();
+    thumbnail_path.push(format!("{}{}.jpg", prefix, version));
+    if thumbnail_path.is_file() {
+        return Ok(thumbnail_path);
+    }
+
+    try!(fs::create_dir_all(&thumbnail_dir));
+
+    info!("Generating {}px thumbnail for book {}", size, bookid);
+    let thumbnail = try!(image::open(cover_path)).thumbnail(size, size);
+
+    try!(write_atomically(&thumbnail_path, |out| {
+        thumbnail.write_to(out, ImageOutputFormat::JPEG(THUMBNAIL_QUALITY))
+            .map_err(ThumbnailError::from)
+    }));
+
+    // Cleaned up only after the rename, and only finished thumbnails of
+    // other versions, since concurrent requests for the same cover may be
+    // writing their temporary files.
+    let current = format!("{}{}.jpg", prefix, version);
+    for entry in try!(fs::read_dir(&thumbnail_dir)) {
+        let entry = try!(entry);
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with(&prefix) && name.ends_with(".jpg") && name != current {
+            debug!("Removing outdated thumbnail {:?}", entry.path());
+            // Another request may have removed it already.
+            if let Err(e) = fs::remove_file(entry.path()) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    return Err(e.into());
+                }
+            }
+        }
+    }
+    Ok(thumbnail_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_size;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("500"), Ok(500));
+        assert_eq!(parse_size("500B"), Ok(500));
+        assert_eq!(parse_size("4k"), Ok(4 << 10));
+        assert_eq!(parse_size("500M"), Ok(500 << 20));
+        assert_eq!(parse_size("500MB"), Ok(500 << 20));
+        assert_eq!(parse_size(" 2gb "), Ok(2 << 30));
+        assert_eq!(parse_size("1T"), Ok(1 << 40));
+        assert!(parse_size("").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("MB").is_err());
+        assert!(parse_size("2X").is_err());
+        assert!(parse_size("-1M").is_err());
+        assert!(parse_size("1.5G").is_err());
+        // Overflows u64
+        assert!(parse_size("20000000T").is_err());
+        assert_eq!(parse_size("16777215T"), Ok(16777215 << 40));
+    }
+}
@@ -0,0 +1,522 @@
+//! Comic archives (CBZ, CBR and CB7) read page by page without converting
+//! them to EPUB.
+//!
+//! Pages are extracted on demand into `comics/{bookid}` in the cache dir,
+//! together with the page list and downscaled copies.
+
+use std::{io, fmt, fs, thread};
+use std::cmp::Ordering;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::mpsc::{channel, Receiver};
+use std::time::{Duration, Instant};
+
+use image;
+use image::{FilterType, ImageDecoder, ImageOutputFormat};
+use image::bmp::BMPDecoder;
+use image::gif::Decoder as GifDecoder;
+use image::jpeg::JPEGDecoder;
+use image::png::PNGDecoder;
+use image::webp::WebpDecoder;
+use serde_json;
+use zip::ZipArchive;
+use zip::result::ZipError;
+
+use cache::{write_atomically, CacheManager, EntryGuard, SourceFingerprint};
+use converter::{spawn_process_group, wait_process_group, Limits,
+                OUTPUT_DRAIN_TIMEOUT_MS};
+
+pub const COMIC_FORMATS: &[&str] = &["CBZ", "CB7", "CBR"];
+
+const IMAGE_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "gif", "webp", "bmp"];
+
+/// Sub-directory of the cache dir holding extracted comic pages
+pub const COMIC_DIR: &str = "comics";
+
+/// File in each comic cache recording the page names in reading order
+const PAGES_FILE: &str = "pages.json";
+
+/// Widths pages are downscaled to; requested widths are rounded up to one of
+/// these to keep the page cache bounded. Wider requests get the original.
+const PAGE_WIDTHS: &[u32] = &[480, 720, 1080, 1440, 2160];
+
+const PAGE_QUALITY: u8 = 85;
+
+/// Largest page image read from an archive, guarding against archives
+/// claiming or inflating to absurd sizes
+const MAX_PAGE_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Largest page, in pixels, decoded for downscaling. A small file can
+/// decode into a huge image, so the size is read from its header first.
+const MAX_PAGE_PIXELS: u64 = 50_000_000;
+
+/// Largest entry listing read from an extractor
+const MAX_LISTING_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Error output of a failed extractor kept for the log
+const MAX_ERROR_OUTPUT: u64 = 64 * 1024;
+
+/// Wall-clock limit of each extractor run; a request is waiting for it.
+const EXTRACTOR_TIMEOUT_SECS: u64 = 60;
+
+pub fn is_comic_format(format: &str) -> bool {
+    COMIC_FORMATS.contains(&format)
+}
+
+/// Picks the comic archive to read among the sources of a book, if any.
+pub fn find_comic_source(sources: &[SourceFingerprint]) -> Option<&SourceFingerprint> {
+    sources.iter()
+        .filter_map(|source| {
+            let format = source.format();
+            COMIC_FORMATS.iter().position(|f| *f == format).map(|cost| (cost, source))
+        })
+        .min_by_key(|&(cost, _)| cost)
+        .map(|(_, source)| source)
+}
+
+#[derive(Debug)]
+pub enum ComicError {
+    IoError(io::Error),
+    ZipError(ZipError),
+    /// External extractor failed, e.g. `unrar` for CBR
+    ExtractorError(String, ExitStatus),
+    ExtractorTimeoutError(String),
+    ImageError(image::ImageError),
+    UnsupportedFormatError(String),
+    NoSuchPageError(usize),
+    PageTooLargeError(String),
+    /// Page dimensions too large to decode
+    PageDimensionsError(String, u32, u32),
+    ListingTooLargeError,
+}
+
+impl Error for ComicError {
+    fn description(&self) -> &str {
+        "comic archive error"
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match self {
+            ComicError::IoError(e) => Some(e),
+            ComicError::ZipError(e) => Some(e),
+            ComicError::ImageError(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for ComicError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ComicError::IoError(e) => write!(f, "Failed to read comic: {}", e),
+            ComicError::ZipError(e) => write!(f, "Failed to read CBZ archive: {}", e),
+            ComicError::ExtractorError(cmd, status) =>
+                write!(f, "{} exited with status {}", cmd, status),
+            ComicError::ExtractorTimeoutError(cmd) =>
+                write!(f, "{} timed out after {} seconds", cmd, EXTRACTOR_TIMEOUT_SECS),
+            ComicError::ImageError(e) => write!(f, "Failed to resize page: {}", e),
+            ComicError::UnsupportedFormatError(format) =>
+                write!(f, "{} is not a comic archive format", format),
+            ComicError::NoSuchPageError(n) => write!(f, "No page {}", n),
+            ComicError::PageTooLargeError(name) =>
+                write!(f, "Page {} is larger than {} bytes", name, MAX_PAGE_SIZE),
+            ComicError::PageDimensionsError(name, width, height) =>
+                write!(f, "Page {} is too large to resize ({}x{})", name, width, height),
+            ComicError::ListingTooLargeError =>
+                write!(f, "Archive listing is larger than {} bytes", MAX_LISTING_SIZE),
+        }
+    }
+}
+
+impl From<io::Error> for ComicError {
+    fn from(e: io::Error) -> Self {
+        ComicError::IoError(e)
+    }
+}
+
+impl From<ZipError> for ComicError {
+    fn from(e: ZipError) -> Self {
+        ComicError::ZipError(e)
+    }
+}
+
+impl From<image::ImageError> for ComicError {
+    fn from(e: image::ImageError) -> Self {
+        ComicError::ImageError(e)
+    }
+}
+
+/// Commands used for archive formats the zip crate can't read
+#[derive(Clone, Debug)]
+pub struct Extractors {
+    pub unrar: String,
+    pub sevenzip: String,
+    /// Resource limits applied to the extractor processes
+    pub limits: Limits,
+}
+
+/// Compares file names the way people number pages, i.e. "page2.jpg" before
+/// "page10.jpg".
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut a = a.chars().peekable();
+    let mut b = b.chars().peekable();
+    loop {
+        let (ca, cb) = match (a.peek().cloned(), b.peek().cloned()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(ca), Some(cb)) => (ca, cb)
+        };
+        if ca.is_ascii_digit() && cb.is_ascii_digit() {
+            let mut na = String::new();
+            while let Some(c) = a.peek().cloned().filter(|c| c.is_ascii_digit()) {
+                na.push(c);
+                a.next();
+            }
+            let mut nb = String::new();
+            while let Some(c) = b.peek().cloned().filter(|c| c.is_ascii_digit()) {
+                nb.push(c);
+                b.next();
+            }
+            let na = na.trim_left_matches('0');
+            let nb = nb.trim_left_matches('0');
+            let ord = na.len().cmp(&nb.len()).then_with(|| na.cmp(nb));
+            if ord != Ordering::Equal {
+                return ord;
+            }
+        } else {
+            let ord = ca.to_lowercase().cmp(cb.to_lowercase());
+            if ord != Ordering::Equal {
+                return ord;
+            }
+            a.next();
+            b.next();
+        }
+    }
+}
+
+/// Tells if an archive entry is a page, skipping e.g. macOS resource forks.
+fn is_page(name: &str) -> bool {
+    let basename = name.rsplit(|c| c == '/' || c == '\\').next().unwrap_or(name);
+    if basename.starts_with('.') || name.starts_with("__MACOSX") {
+        return false;
+    }
+    Path::new(basename).extension()
+        .map(|ext| IMAGE_EXTENSIONS.contains(&ext.to_string_lossy().to_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+/// Reads up to `limit` bytes of `output` on another thread, so that the
+/// process isn't blocked on a full pipe while it's waited for. The pipe is
+/// closed after that, failing the process if it has more to write.
+fn read_limited<R>(output: R, limit: u64) -> Receiver<io::Result<Vec<u8>>>
+    where R: Read + Send + 'static {
+    let (sender, receiver) = channel();
+    thread::spawn(move || {
+        let mut data = Vec::new();
+        let result = output.take(limit).read_to_end(&mut data).map(|_| data);
+        let _ = sender.send(result);
+    });
+    receiver
+}
+
+/// Runs an extractor under `limits` and the extractor timeout, returning its
+/// output, or None if the output is longer than `max_output` bytes.
+fn run_extractor(mut command: Command, name: &str, limits: Limits, max_output: u64)
+                 -> Result<Option<Vec<u8>>, ComicError> {
+    command.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped());
+    let mut child = try!(spawn_process_group(&mut command, limits));
+    let stdout = read_limited(child.stdout.take().unwrap(), max_output + 1);
+    let stderr = read_limited(child.stderr.take().unwrap(), MAX_ERROR_OUTPUT);
+
+    let started_at = Instant::now();
+    let timeout = Duration::from_secs(EXTRACTOR_TIMEOUT_SECS);
+    let status = try!(wait_process_group(&mut child, || {
+        if started_at.elapsed() > timeout {
+            Err(ComicError::ExtractorTimeoutError(name.to_string()))
+        } else {
+            Ok(())
+        }
+    }, ComicError::IoError));
+
+    let drain_timeout = Duration::from_millis(OUTPUT_DRAIN_TIMEOUT_MS);
+    let output = match stdout.recv_timeout(drain_timeout) {
+        Ok(result) => try!(result),
+        Err(_) => return Err(ComicError::ExtractorTimeoutError(name.to_string()))
+    };
+    if output.len() as u64 > max_output {
+        // The status tells nothing since the pipe was closed early.
+        return Ok(None);
+    }
+    if !status.success() {
+        let error = stderr.recv_timeout(drain_timeout).ok()
+            .and_then(|result| result.ok())
+            .unwrap_or_default();
+        warn!("{} failed: {}", name, String::from_utf8_lossy(&error));
+        return Err(ComicError::ExtractorError(name.to_string(), status));
+    }
+    Ok(Some(output))
+}
+
+/// Reads the dimensions of a page from its header without decoding it.
+fn page_dimensions(path: &Path, ext: &str) -> Result<(u32, u32), ComicError> {
+    let file = BufReader::new(try!(File::open(path)));
+    let dimensions = match ext {
+        "jpg" | "jpeg" => JPEGDecoder::new(file).dimensions(),
+        "png" => PNGDecoder::new(file).dimensions(),
+        "gif" => GifDecoder::new(file).dimensions(),
+        "webp" => WebpDecoder::new(file).dimensions(),
+        "bmp" => BMPDecoder::new(file).dimensions(),
+        _ => Err(image::ImageError::UnsupportedError(
+            format!("Unsupported page format: {}", ext)))
+    };
+    Ok(try!(dimensions))
+}
+
+/// A comic archive in one of `COMIC_FORMATS`
+pub struct ComicArchive<'a> {
+    path: &'a Path,
+    format: String,
+    extractors: &'a Extractors,
+}
+
+impl<'a> ComicArchive<'a> {
+    pub fn new(source: &'a SourceFingerprint, extractors: &'a Extractors)
+               -> Result<Self, ComicError> {
+        let format = source.format();
+        if !is_comic_format(&format) {
+            return Err(ComicError::UnsupportedFormatError(format));
+        }
+        Ok(ComicArchive {
+            path: &source.path,
+            format: format,
+            extractors: extractors,
+        })
+    }
+
+    fn list_entries(&self) -> Result<Vec<String>, ComicError> {
+        match self.format.as_str() {
+            "CBZ" => {
+                let mut archive = try!(ZipArchive::new(try!(File::open(self.path))));
+                let mut names = Vec::new();
+                for i in 0..archive.len() {
+                    names.push(try!(archive.by_index(i)).name().to_string());
+                }
+                Ok(names)
+            }
+            "CBR" => {
+                let mut command = Command::new(&self.extractors.unrar);
+                command.arg("lb").arg("--").arg(self.path);
+                let out = try!(try!(self.run(command, MAX_LISTING_SIZE))
+                               .ok_or(ComicError::ListingTooLargeError));
+                Ok(String::from_utf8_lossy(&out).lines().map(|s| s.to_string()).collect())
+            }
+            _ => {
+                // The technical listing has a "Path = " line per entry after
+                // the "----------" separator; the one before is the archive.
+                let mut command = Command::new(&self.extractors.sevenzip);
+                command.arg("l").arg("-slt").arg("--").arg(self.path);
+                let out = try!(try!(self.run(command, MAX_LISTING_SIZE))
+                               .ok_or(ComicError::ListingTooLargeError));
+                Ok(String::from_utf8_lossy(&out).lines()
+                   .skip_while(|line| !line.starts_with("----------"))
+                   .filter(|line| line.starts_with("Path = "))
+                   .map(|line| line["Path = ".len()..].to_string())
+                   .collect())
+            }
+        }
+    }
+
+    /// Runs the extractor of the archive format.
+    fn run(&self, command: Command, max_output: u64)
+           -> Result<Option<Vec<u8>>, ComicError> {
+        let name = if self.format == "CBR" {
+            &self.extractors.unrar
+        } else {
+            &self.extractors.sevenzip
+        };
+        run_extractor(command, name, self.extractors.limits, max_output)
+    }
+
+    /// Lists the page images in reading order.
+    pub fn pages(&self) -> Result<Vec<String>, ComicError> {
+        let mut pages: Vec<String> = try!(self.list_entries()).into_iter()
+            .filter(|name| is_page(name))
+            .collect();
+        pages.sort_by(|a, b| natural_cmp(a, b));
+        Ok(pages)
+    }
+
+    pub fn read(&self, name: &str) -> Result<Vec<u8>, ComicError> {
+        match self.format.as_str() {
+            "CBZ" => {
+                let mut archive = try!(ZipArchive::new(try!(File::open(self.path))));
+                let entry = try!(archive.by_name(name));
+                // The size in the archive isn't trusted for allocation.
+                let mut data = Vec::new();
+                try!(entry.take(MAX_PAGE_SIZE + 1).read_to_end(&mut data));
+                if data.len() as u64 > MAX_PAGE_SIZE {
+                    return Err(ComicError::PageTooLargeError(name.to_string()));
+                }
+                Ok(data)
+            }
+            "CBR" => {
+                let mut command = Command::new(&self.extractors.unrar);
+                command.arg("p").arg("-inul").arg("--").arg(self.path).arg(name);
+                try!(self.run(command, MAX_PAGE_SIZE))
+                    .ok_or_else(|| ComicError::PageTooLargeError(name.to_string()))
+            }
+            _ => {
+                let mut command = Command::new(&self.extractors.sevenzip);
+                command.arg("e").arg("-so").arg("--").arg(self.path).arg(name);
+                try!(self.run(command, MAX_PAGE_SIZE))
+                    .ok_or_else(|| ComicError::PageTooLargeError(name.to_string()))
+            }
+        }
+    }
+}
+
+fn is_fresh(dir: &Path, source: &SourceFingerprint) -> bool {
+    SourceFingerprint::read_from(dir).map(|cached| cached == *source).unwrap_or(false)
+}
+
+/// Extracted pages of a comic in the cache dir
+pub struct ComicCache<'a> {
+    dir: PathBuf,
+    source: &'a SourceFingerprint,
+    archive: ComicArchive<'a>,
+    /// Keeps the pages from being discarded while they're being used
+    _guard: EntryGuard,
+}
+
+impl<'a> ComicCache<'a> {
+    /// Opens the page cache of the book, discarding pages of an outdated
+    /// version of the source.
+    ///
+    /// The lock of the book is held shared while the cache is open, and
+    /// exclusively to discard pages, so that no request sees its files
+    /// removed midway.
+    pub fn open(cache: &CacheManager, bookid: i64, source: &'a SourceFingerprint,
+                extractors: &'a Extractors)
+                -> Result<Self, ComicError> {
+        let archive = try!(ComicArchive::new(source, extractors));
+        let dir = cache.comic_path(bookid);
+        let locks = cache.comic_locks();
+
+        let mut guard = locks.read(bookid);
+        if !is_fresh(&dir, source) {
+            drop(guard);
+            let exclusive = locks.write(bookid);
+            // Another request may have done it while waiting for the lock.
+            if !is_fresh(&dir, source) {
+                if dir.exists() {
+                    info!("Discarding outdated pages of book {}", bookid);
+                    try!(fs::remove_dir_all(&dir));
+                }
+                try!(fs::create_dir_all(&dir));
+                try!(source.write_to(&dir));
+            }
+            guard = exclusive.downgrade();
+        }
+        Ok(ComicCache {
+            dir: dir,
+            source: source,
+            archive: archive,
+            _guard: guard,
+        })
+    }
+
+    /// Page names in reading order; listing RARs is slow, so it's cached.
+    pub fn pages(&self) -> Result<Vec<String>, ComicError> {
+        let mut path = self.dir.clone();
+        path.push(PAGES_FILE);
+        if let Some(pages) = File::open(&path).ok()
+            .and_then(|file| serde_json::from_reader(file).ok()) {
+            return Ok(pages);
+        }
+        let pages = try!(self.archive.pages());
+        try!(write_atomically(&path, |out| {
+            serde_json::to_writer(out, &pages)
+                .map_err(|e| ComicError::IoError(io::Error::new(io::ErrorKind::Other, e)))
+        }));
+        Ok(pages)
+    }
+
+    /// Returns the path of page `n` (0-origin), downscaled to fit in `width`
+    /// if given, extracting it if necessary.
+    pub fn page(&self, n: usize, width: Option<u32>) -> Result<PathBuf, ComicError> {
+        let pages = try!(self.pages());
+        let name = match pages.get(n) {
+            Some(name) => name,
+            None => return Err(ComicError::NoSuchPageError(n))
+        };
+        let ext = Path::new(name).extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+
+        let mut original_path = self.dir.clone();
+        original_path.push(format!("{}.{}", n, ext));
+        if !original_path.is_file() {
+            debug!("Extracting page {} ({}) from {:?}", n, name, self.source.path);
+            let data = try!(self.archive.read(name));
+            try!(write_atomically(&original_path, |out| {
+                out.write_all(&data).map_err(ComicError::IoError)
+            }));
+        }
+
+        let width = match width.and_then(|w| PAGE_WIDTHS.iter().find(|&&pw| pw >= w)) {
+            Some(&width) => width,
+            None => return Ok(original_path)
+        };
+        let mut scaled_path = self.dir.clone();
+        scaled_path.push(format!("{}-{}.jpg", n, width));
+        if scaled_path.is_file() {
+            return Ok(scaled_path);
+        }
+
+        let (page_width, page_height) = try!(page_dimensions(&original_path, &ext));
+        if page_width <= width {
+            // Never upscale
+            return Ok(original_path);
+        }
+        if page_width as u64 * page_height as u64 > MAX_PAGE_PIXELS {
+            return Err(ComicError::PageDimensionsError(name.clone(), page_width,
+                                                       page_height));
+        }
+        let page = try!(image::open(&original_path));
+        let scaled = page.resize(width, u32::max_value(), FilterType::Lanczos3);
+        try!(write_atomically(&scaled_path, |out| {
+            scaled.write_to(out, ImageOutputFormat::JPEG(PAGE_QUALITY))
+                .map_err(ComicError::ImageError)
+        }));
+        Ok(scaled_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::natural_cmp;
+    use std::cmp::Ordering;
+
+    #[test]
+    fn natural_order() {
+        assert_eq!(natural_cmp("page2.jpg", "page10.jpg"), Ordering::Less);
+        assert_eq!(natural_cmp("page10.jpg", "page2.jpg"), Ordering::Greater);
+        assert_eq!(natural_cmp("page02.jpg", "page2.jpg"), Ordering::Equal);
+        assert_eq!(natural_cmp("Page1.jpg", "page1.jpg"), Ordering::Equal);
+        assert_eq!(natural_cmp("ch1/p10", "ch2/p1"), Ordering::Less);
+        assert_eq!(natural_cmp("p1", "p1a"), Ordering::Less);
+        assert_eq!(natural_cmp("a", "b"), Ordering::Less);
+        assert_eq!(natural_cmp("", "a"), Ordering::Less);
+        // Numbers beyond u64 are still compared by value
+        assert_eq!(natural_cmp("p99999999999999999999", "p100000000000000000000"),
+                   Ordering::Less);
+
+        let mut names = vec!["p10.png", "p1.png", "p9.png", "cover.png", "p100.png"];
+        names.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(names, vec!["cover.png", "p1.png", "p9.png", "p10.png", "p100.png"]);
+    }
+}
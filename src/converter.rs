@@ -0,0 +1,747 @@
+//! Backends converting e-books of various formats into EPUB for the reader.
+
+use std::{fmt, fs, io, thread};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, Read, Write, BufReader};
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::sync::mpsc::{channel, Sender};
+use std::time::{Duration, Instant};
+
+use libc;
+
+use pulldown_cmark;
+use pulldown_cmark::{Event, Parser};
+use serde_json::Value;
+use xml::reader::{EventReader, XmlEvent};
+use zip::{CompressionMethod, ZipWriter};
+use zip::write::FileOptions;
+
+use db::ConversionOptions;
+use jobs::{CancelFlag, JobRegistry};
+use worker::ConversionError;
+use worker::ConversionError::{EpubConversionCommandError, EpubConversionError,
+                              NativeConversionError, CacheWriteError,
+                              TimeoutError, CancelledError};
+
+/// How often running converter processes are checked for completion,
+/// timeouts and cancellation
+const POLL_INTERVAL_MS: u64 = 200;
+
+/// How long to wait for the rest of the output once a converter exits;
+/// processes that left its process group may keep the pipes open forever.
+pub const OUTPUT_DRAIN_TIMEOUT_MS: u64 = 5000;
+
+/// Resource limits applied to converter processes
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Limits {
+    /// CPU time in seconds
+    pub cpu_time: Option<u64>,
+    /// Address space in bytes
+    pub memory: Option<u64>,
+    /// Size of each file written, in bytes
+    pub file_size: Option<u64>,
+}
+
+/// Conversion log of a job, shared with the threads reading the output of
+/// converter processes.
+#[derive(Clone)]
+pub struct ConversionLog {
+    file: Arc<Mutex<File>>,
+}
+
+impl ConversionLog {
+    /// Creates the log, replacing the one of the previous job.
+    pub fn create(path: &Path) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            try!(fs::create_dir_all(parent));
+        }
+        Ok(ConversionLog {
+            file: Arc::new(Mutex::new(try!(File::create(path)))),
+        })
+    }
+
+    pub fn write_line(&self, line: &str) {
+        let mut file = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Err(e) = writeln!(file, "{}", line) {
+            debug!("Failed to write the conversion log: {}", e);
+        }
+    }
+}
+
+/// Per-job state letting a conversion be interrupted, and report its
+/// progress and output.
+pub struct JobControl {
+    pub bookid: i64,
+    /// Generation of the job, so that late output of its processes isn't
+    /// reported as progress of a later job
+    pub generation: u64,
+    pub started_at: Instant,
+    /// Wall-clock limit of the whole job
+    pub timeout: Option<Duration>,
+    pub cancel: CancelFlag,
+    pub jobs: JobRegistry,
+    pub log: Option<ConversionLog>,
+}
+
+impl JobControl {
+    pub fn log(&self, line: &str) {
+        if let Some(ref log) = self.log {
+            log.write_line(line);
+        }
+    }
+
+    /// Fails if the job was cancelled or ran out of time.
+    pub fn check(&self) -> Result<(), ConversionError> {
+        if self.cancel.is_cancelled() {
+            return Err(CancelledError);
+        }
+        match self.timeout {
+            Some(timeout) if self.started_at.elapsed() > timeout =>
+                Err(TimeoutError(timeout)),
+            _ => Ok(())
+        }
+    }
+}
+
+pub trait Converter: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Converts `src` into an EPUB file at `dest`. Backends are free to
+    /// ignore the per-book `options` they don't understand, and should
+    /// return as soon as `control` reports an interruption.
+    fn convert(&self, src: &Path, dest: &Path, options: &ConversionOptions,
+               control: &JobControl)
+               -> Result<(), ConversionError>;
+}
+
+/// Runs in the forked child before exec. It puts the child in a new process
+/// group so that its whole tree can be killed, and applies the limits.
+fn prepare_child(limits: &Limits) -> io::Result<()> {
+    if unsafe { libc::setpgid(0, 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let resources = [
+        (libc::RLIMIT_CPU, limits.cpu_time),
+        (libc::RLIMIT_AS, limits.memory),
+        (libc::RLIMIT_FSIZE, limits.file_size),
+    ];
+    for &(resource, limit) in resources.iter() {
+        if let Some(limit) = limit {
+            let rlim = libc::rlimit {
+                rlim_cur: limit as libc::rlim_t,
+                rlim_max: limit as libc::rlim_t,
+            };
+            if unsafe { libc::setrlimit(resource, &rlim) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+    Ok(())
+}
+
+fn signal_process_group(child: &Child) {
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+}
+
+fn kill_process_group(child: &mut Child) {
+    signal_process_group(child);
+    if let Err(e) = child.wait() {
+        warn!("Failed to reap process {}: {}", child.id(), e);
+    }
+}
+
+/// Spawns `command` as the leader of a new process group with `limits`
+/// applied, so that `wait_process_group` can kill its whole tree.
+pub fn spawn_process_group(command: &mut Command, limits: Limits) -> io::Result<Child> {
+    command.before_exec(move || prepare_child(&limits));
+    command.spawn()
+}
+
+/// Waits for a child spawned by `spawn_process_group`, killing its process
+/// group as soon as `check` fails. Errors waiting for the child are turned
+/// into `E` with `io_error`.
+pub fn wait_process_group<E, F, G>(child: &mut Child, mut check: F, io_error: G)
+                                   -> Result<ExitStatus, E>
+    where E: fmt::Display, F: FnMut() -> Result<(), E>, G: FnOnce(io::Error) -> E {
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => {
+                // Leftover children, e.g. calibre's worker processes, would
+                // keep the pipes open.
+                signal_process_group(child);
+                return Ok(status);
+            },
+            Ok(None) => {},
+            Err(e) => {
+                kill_process_group(child);
+                return Err(io_error(e));
+            }
+        }
+        if let Err(e) = check() {
+            info!("Killing process {}: {}", child.id(), e);
+            kill_process_group(child);
+            return Err(e);
+        }
+        thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+    }
+}
+
+/// Parses progress lines of ebook-convert like "34% Running transforms...".
+fn parse_progress(line: &str) -> Option<u8> {
+    let line = line.trim_left();
+    let digits = line.find(|c: char| !c.is_ascii_digit()).unwrap_or(line.len());
+    if digits == 0 || !line[digits..].starts_with('%') {
+        return None;
+    }
+    line[..digits].parse::<u8>().ok().filter(|p| *p <= 100)
+}
+
+/// Copies the output of a converter process into the job log line by line,
+/// reporting progress found in it. Signals `done` at the end of the output.
+fn read_output<R>(output: R, control: &JobControl, parse: bool, done: Sender<()>)
+    where R: Read + Send + 'static {
+    let log = control.log.clone();
+    let jobs = control.jobs.clone();
+    let bookid = control.bookid;
+    let generation = control.generation;
+    thread::spawn(move || {
+        let mut reader = BufReader::new(output);
+        let mut buf = Vec::new();
+        loop {
+            buf.clear();
+            match reader.read_until(b'\n', &mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {}
+            }
+            // The output isn't necessarily UTF-8.
+            let line = String::from_utf8_lossy(&buf);
+            let line = line.trim_right();
+            if let Some(ref log) = log {
+                log.write_line(line);
+            }
+            if parse {
+                if let Some(percent) = parse_progress(line) {
+                    jobs.progress(bookid, generation, percent);
+                }
+            }
+        }
+        let _ = done.send(());
+    });
+}
+
+/// Runs a converter process to completion, killing it together with its
+/// children if the job is interrupted.
+fn run_converter(mut command: Command, limits: Limits, control: &JobControl)
+                 -> Result<(), ConversionError> {
+    command.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped());
+    let mut child = try!(spawn_process_group(&mut command, limits)
+                         .map_err(EpubConversionCommandError));
+    let (done, drained) = channel();
+    let mut readers = 0;
+    if let Some(stdout) = child.stdout.take() {
+        read_output(stdout, control, true, done.clone());
+        readers += 1;
+    }
+    if let Some(stderr) = child.stderr.take() {
+        read_output(stderr, control, false, done.clone());
+        readers += 1;
+    }
+    let code = try!(wait_process_group(&mut child, || control.check(),
+                                       EpubConversionCommandError));
+    // Make sure that the whole output is in the log, but don't wait for
+    // processes that escaped the process group.
+    let deadline = Instant::now() + Duration::from_millis(OUTPUT_DRAIN_TIMEOUT_MS);
+    for _ in 0..readers {
+        let now = Instant::now();
+        if now >= deadline || drained.recv_timeout(deadline - now).is_err() {
+            warn!("Gave up reading the output of converter process {}", child.id());
+            break;
+        }
+    }
+    if code.success() { Ok(()) } else { Err(EpubConversionError(code)) }
+}
+
+enum OptionKind {
+    /// `--name VALUE`
+    Value,
+    /// `--name` if the option is on
+    Flag,
+    /// `--disable-name` if the option is off
+    NegatedFlag,
+}
+
+/// Calibre conversion options that affect the look of the browser preview.
+/// Output-specific ones are left out since we always produce EPUB with the
+/// tablet profile.
+const CALIBRE_OPTIONS: &[(&str, OptionKind)] = &[
+    // Look & feel
+    ("base_font_size", OptionKind::Value),
+    ("font_size_mapping", OptionKind::Value),
+    ("minimum_line_height", OptionKind::Value),
+    ("line_height", OptionKind::Value),
+    ("embed_font_family", OptionKind::Value),
+    ("change_justification", OptionKind::Value),
+    ("extra_css", OptionKind::Value),
+    ("filter_css", OptionKind::Value),
+    ("input_encoding", OptionKind::Value),
+    ("disable_font_rescaling", OptionKind::Flag),
+    ("linearize_tables", OptionKind::Flag),
+    ("smarten_punctuation", OptionKind::Flag),
+    ("unsmarten_punctuation", OptionKind::Flag),
+    ("asciiize", OptionKind::Flag),
+    ("keep_ligatures", OptionKind::Flag),
+    ("expand_css", OptionKind::Flag),
+    ("remove_paragraph_spacing", OptionKind::Flag),
+    ("remove_paragraph_spacing_indent_size", OptionKind::Value),
+    ("insert_blank_line", OptionKind::Flag),
+    ("insert_blank_line_size", OptionKind::Value),
+    // Page setup
+    ("margin_top", OptionKind::Value),
+    ("margin_bottom", OptionKind::Value),
+    ("margin_left", OptionKind::Value),
+    ("margin_right", OptionKind::Value),
+    // Heuristic processing
+    ("enable_heuristics", OptionKind::Flag),
+    ("markup_chapter_headings", OptionKind::NegatedFlag),
+    ("italicize_common_cases", OptionKind::NegatedFlag),
+    ("fix_indents", OptionKind::NegatedFlag),
+    ("unwrap_lines", OptionKind::NegatedFlag),
+    ("delete_blank_paragraphs", OptionKind::NegatedFlag),
+    ("format_scene_breaks", OptionKind::NegatedFlag),
+    ("dehyphenate", OptionKind::NegatedFlag),
+    ("renumber_headings", OptionKind::NegatedFlag),
+    ("html_unwrap_factor", OptionKind::Value),
+    ("replace_scene_breaks", OptionKind::Value),
+    // Structure detection
+    ("chapter", OptionKind::Value),
+    ("chapter_mark", OptionKind::Value),
+    ("page_breaks_before", OptionKind::Value),
+    ("remove_first_image", OptionKind::Flag),
+    ("insert_metadata", OptionKind::Flag),
+];
+
+/// Translates stored Calibre options into `ebook-convert` arguments.
+fn calibre_args(options: &ConversionOptions) -> Vec<String> {
+    let mut args = Vec::new();
+    for &(name, ref kind) in CALIBRE_OPTIONS {
+        let flag = name.replace('_', "-");
+        match (kind, options.get(name)) {
+            (&OptionKind::Value, Some(&Value::String(ref s))) if !s.is_empty() => {
+                args.push(format!("--{}", flag));
+                args.push(s.clone());
+            },
+            (&OptionKind::Value, Some(&Value::Number(ref n))) => {
+                args.push(format!("--{}", flag));
+                args.push(n.to_string());
+            },
+            (&OptionKind::Flag, Some(&Value::Bool(true))) =>
+                args.push(format!("--{}", flag)),
+            (&OptionKind::NegatedFlag, Some(&Value::Bool(false))) =>
+                args.push(format!("--disable-{}", flag)),
+            _ => {}
+        }
+    }
+    args
+}
+
+/// Converts with Calibre's `ebook-convert`.
+pub struct CalibreConverter {
+    bin: String,
+    limits: Limits,
+}
+
+impl CalibreConverter {
+    pub fn new(bin: &str, limits: Limits) -> Self {
+        CalibreConverter { bin: bin.to_string(), limits: limits }
+    }
+}
+
+impl Converter for CalibreConverter {
+    fn name(&self) -> &str {
+        "calibre"
+    }
+
+    fn convert(&self, src: &Path, dest: &Path, options: &ConversionOptions,
+               control: &JobControl)
+               -> Result<(), ConversionError> {
+        let mut command = Command::new(&self.bin);
+        command
+            .arg(src)
+            .arg(dest)
+            .arg("--no-default-epub-cover")
+            .arg("--output-profile")
+            .arg("tablet")
+            .args(calibre_args(options));
+        run_converter(command, self.limits, control)
+    }
+}
+
+/// Converts with pandoc, which infers the input format from the extension.
+pub struct PandocConverter {
+    bin: String,
+    limits: Limits,
+}
+
+impl PandocConverter {
+    pub fn new(bin: &str, limits: Limits) -> Self {
+        PandocConverter { bin: bin.to_string(), limits: limits }
+    }
+}
+
+impl Converter for PandocConverter {
+    fn name(&self) -> &str {
+        "pandoc"
+    }
+
+    fn convert(&self, src: &Path, dest: &Path, _options: &ConversionOptions,
+               control: &JobControl)
+               -> Result<(), ConversionError> {
+        let mut command = Command::new(&self.bin);
+        if source_format(src) == "TXT" {
+            // pandoc has no plain text reader, and markdown is the closest.
+            command.arg("--from").arg("markdown");
+        }
+        command
+            .arg("--to").arg("epub")
+            .arg("--output").arg(dest)
+            .arg(src);
+        run_converter(command, self.limits, control)
+    }
+}
+
+/// Formats the native converter can handle
+const NATIVE_FORMATS: &[&str] = &["TXT", "MD", "MARKDOWN", "FB2"];
+
+/// Converts simple formats in-process by wrapping them into a single-chapter
+/// EPUB, so that no external tool is needed.
+pub struct NativeConverter;
+
+fn escape_xml(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(c)
+        }
+    }
+    escaped
+}
+
+fn read_text(src: &Path) -> Result<String, ConversionError> {
+    let mut bytes = Vec::new();
+    try!(File::open(src).and_then(|mut f| f.read_to_end(&mut bytes))
+         .map_err(|e| NativeConversionError(format!("Failed to read source: {}", e))));
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+fn text_to_xhtml(text: &str) -> String {
+    let mut body = String::new();
+    for para in text.split("\n\n").map(|p| p.trim()).filter(|p| !p.is_empty()) {
+        body.push_str("<p>");
+        body.push_str(&escape_xml(para).replace('\n', "<br/>"));
+        body.push_str("</p>\n");
+    }
+    body
+}
+
+/// Raw HTML in the markdown is kept as text, since it's rarely well-formed
+/// XHTML.
+fn markdown_to_xhtml(text: &str) -> String {
+    let mut body = String::new();
+    let events = Parser::new(text).map(|event| match event {
+        Event::Html(html) | Event::InlineHtml(html) => Event::Text(html),
+        event => event
+    });
+    pulldown_cmark::html::push_html(&mut body, events);
+    body
+}
+
+/// Maps FictionBook elements to XHTML ones; unlisted elements are dropped
+/// while their text is kept.
+fn fb2_element(name: &str) -> Option<&'static str> {
+    match name {
+        "p" | "v" | "text-author" => Some("p"),
+        "title" => Some("h2"),
+        "subtitle" => Some("h3"),
+        "emphasis" => Some("em"),
+        "strong" => Some("strong"),
+        "strikethrough" => Some("del"),
+        "sub" => Some("sub"),
+        "sup" => Some("sup"),
+        "code" => Some("code"),
+        "epigraph" | "cite" | "poem" => Some("blockquote"),
+        "section" | "stanza" => Some("div"),
+        _ => None
+    }
+}
+
+/// Converts the bodies of a FictionBook into XHTML, returning the book
+/// title as well.
+fn fb2_to_xhtml(src: &Path) -> Result<(String, String), ConversionError> {
+    let file = try!(File::open(src).map_err(
+        |e| NativeConversionError(format!("Failed to read source: {}", e))));
+    let mut title = String::new();
+    let mut body = String::new();
+    let mut in_body = false;
+    let mut in_book_title = false;
+
+    for e in EventReader::new(BufReader::new(file)) {
+        let e = try!(e.map_err(|e| NativeConversionError(format!("Malformed FB2: {}", e))));
+        match e {
+            XmlEvent::StartElement { name, .. } => {
+                match name.local_name.as_str() {
+                    "body" => in_body = true,
+                    "book-title" => in_book_title = true,
+                    "empty-line" if in_body => body.push_str("<br/>"),
+                    n if in_body => if let Some(tag) = fb2_element(n) {
+                        body.push_str(&format!("<{}>", tag));
+                    },
+                    _ => {}
+                }
+            },
+            XmlEvent::EndElement { name } => {
+                match name.local_name.as_str() {
+                    "body" => in_body = false,
+                    "book-title" => in_book_title = false,
+                    n if in_body => if let Some(tag) = fb2_element(n) {
+                        body.push_str(&format!("</{}>\n", tag));
+                    },
+                    _ => {}
+                }
+            },
+            XmlEvent::Characters(text) => {
+                if in_body {
+                    body.push_str(&escape_xml(&text));
+                } else if in_book_title {
+                    title.push_str(&text);
+                }
+            },
+            _ => {}
+        }
+    }
+    Ok((title, body))
+}
+
+const CONTAINER_XML: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles>
+    <rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/>
+  </rootfiles>
+</container>
+"#;
+
+/// Writes an EPUB with `body`, which must be well-formed XHTML, as its only
+/// chapter.
+fn write_epub(dest: &Path, title: &str, body: &str) -> Result<(), ConversionError> {
+    let title = escape_xml(title);
+    let chapter = "text.xhtml";
+    let chapter_doc = format!(r#"<?xml version="1.0" encoding="UTF-8"?>
+<html xmlns="http://www.w3.org/1999/xhtml">
+<head><title>{}</title></head>
+<body>
+{}
+</body>
+</html>
+"#, title, body);
+    let opf = format!(r#"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="2.0" unique-identifier="bookid">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:title>{0}</dc:title>
+    <dc:identifier id="bookid">urn:weblibri:{0}</dc:identifier>
+    <dc:language>und</dc:language>
+  </metadata>
+  <manifest>
+    <item id="ncx" href="toc.ncx" media-type="application/x-dtbncx+xml"/>
+    <item id="text" href="{1}" media-type="application/xhtml+xml"/>
+  </manifest>
+  <spine toc="ncx">
+    <itemref idref="text"/>
+  </spine>
+</package>
+"#, title, chapter);
+    let ncx = format!(r#"<?xml version="1.0" encoding="UTF-8"?>
+<ncx xmlns="http://www.daisy.org/z3986/2005/ncx/" version="2005-1">
+  <head/>
+  <docTitle><text>{0}</text></docTitle>
+  <navMap>
+    <navPoint id="text" playOrder="1">
+      <navLabel><text>{0}</text></navLabel>
+      <content src="{1}"/>
+    </navPoint>
+  </navMap>
+</ncx>
+"#, title, chapter);
+
+    let file = try!(File::create(dest).map_err(CacheWriteError));
+    let mut zip = ZipWriter::new(file);
+    let stored = FileOptions::default().compression_method(CompressionMethod::Stored);
+    let deflated = FileOptions::default();
+    let entries = [
+        ("mimetype", "application/epub+zip".to_string(), stored),
+        ("META-INF/container.xml", CONTAINER_XML.to_string(), deflated),
+        ("OEBPS/content.opf", opf, deflated),
+        ("OEBPS/toc.ncx", ncx, deflated),
+    ];
+    let chapter_path = format!("OEBPS/{}", chapter);
+    for &(ref name, ref content, options) in entries.iter() {
+        try!(zip.start_file(*name, options)
+             .map_err(|e| NativeConversionError(e.to_string())));
+        try!(zip.write_all(content.as_bytes()).map_err(CacheWriteError));
+    }
+    try!(zip.start_file(chapter_path, deflated)
+         .map_err(|e| NativeConversionError(e.to_string())));
+    try!(zip.write_all(chapter_doc.as_bytes()).map_err(CacheWriteError));
+    try!(zip.finish().map_err(|e| NativeConversionError(e.to_string())));
+    Ok(())
+}
+
+impl Converter for NativeConverter {
+    fn name(&self) -> &str {
+        "native"
+    }
+
+    /// Runs in-process, so it can only be interrupted before starting.
+    fn convert(&self, src: &Path, dest: &Path, _options: &ConversionOptions,
+               control: &JobControl)
+               -> Result<(), ConversionError> {
+        try!(control.check());
+        let stem = src.file_stem().map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        match source_format(src).as_str() {
+            "TXT" => write_epub(dest, &stem, &text_to_xhtml(&try!(read_text(src)))),
+            "MD" | "MARKDOWN" =>
+                write_epub(dest, &stem, &markdown_to_xhtml(&try!(read_text(src)))),
+            "FB2" => {
+                let (title, body) = try!(fb2_to_xhtml(src));
+                let title = if title.trim().is_empty() { stem } else { title };
+                write_epub(dest, title.trim(), &body)
+            },
+            format => Err(NativeConversionError(
+                format!("Unsupported source format: {}", format)))
+        }
+    }
+}
+
+/// Calibre format name of a file, i.e. its upper-cased extension.
+pub fn source_format(path: &Path) -> String {
+    path.extension()
+        .map(|ext| ext.to_string_lossy().to_uppercase())
+        .unwrap_or_default()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backend {
+    Calibre,
+    Pandoc,
+    Native,
+}
+
+/// Parses "FORMAT=BACKEND" as given to `--converter-for`.
+pub fn parse_backend_assignment(s: &str) -> Result<(String, Backend), String> {
+    let mut kv = s.splitn(2, '=');
+    let (format, backend) = match (kv.next(), kv.next()) {
+        (Some(f), Some(b)) => (f.trim().to_uppercase(), b.trim()),
+        _ => return Err(format!("Expected FORMAT=BACKEND: {}", s))
+    };
+    let backend = match backend {
+        "calibre" => Backend::Calibre,
+        "pandoc" => Backend::Pandoc,
+        "native" => Backend::Native,
+        _ => return Err(format!("Unknown converter backend: {}", backend))
+    };
+    if backend == Backend::Native && !NATIVE_FORMATS.contains(&format.as_str()) {
+        return Err(format!("The native converter doesn't support {}", format));
+    }
+    Ok((format, backend))
+}
+
+/// Converter backends chosen per source format.
+pub struct Converters {
+    default: Arc<Converter>,
+    by_format: HashMap<String, Arc<Converter>>,
+}
+
+impl Converters {
+    /// Uses Calibre for every format except those in `assignments`.
+    pub fn new(calibre_bin: &str, pandoc_bin: &str,
+               assignments: &[(String, Backend)], limits: Limits) -> Self {
+        let calibre: Arc<Converter> = Arc::new(CalibreConverter::new(calibre_bin, limits));
+        let pandoc: Arc<Converter> = Arc::new(PandocConverter::new(pandoc_bin, limits));
+        let native: Arc<Converter> = Arc::new(NativeConverter);
+
+        let mut by_format = HashMap::new();
+        for &(ref format, backend) in assignments {
+            let converter = match backend {
+                Backend::Calibre => calibre.clone(),
+                Backend::Pandoc => pandoc.clone(),
+                Backend::Native => native.clone(),
+            };
+            by_format.insert(format.clone(), converter);
+        }
+        Converters {
+            default: calibre,
+            by_format: by_format,
+        }
+    }
+
+    pub fn for_format(&self, format: &str) -> &Converter {
+        &**self.by_format.get(format).unwrap_or(&self.default)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{calibre_args, parse_progress};
+    use db::ConversionOptions;
+    use serde_json::Value;
+
+    #[test]
+    fn calibre_option_args() {
+        let mut options = ConversionOptions::new();
+        options.insert("base_font_size".to_string(), Value::from(12));
+        options.insert("margin_top".to_string(), Value::from(1.5));
+        options.insert("extra_css".to_string(), Value::String(String::new()));
+        options.insert("smarten_punctuation".to_string(), Value::Bool(true));
+        options.insert("asciiize".to_string(), Value::Bool(false));
+        options.insert("unwrap_lines".to_string(), Value::Bool(false));
+        options.insert("dehyphenate".to_string(), Value::Bool(true));
+        options.insert("linearize_tables".to_string(), Value::String("yes".to_string()));
+        options.insert("chapter".to_string(), Value::String("//h:h1".to_string()));
+        // Options of other formats, and ones that could break the output
+        options.insert("output_profile".to_string(), Value::String("kindle".to_string()));
+        options.insert("read_metadata_from_opf".to_string(), Value::Bool(true));
+
+        assert_eq!(calibre_args(&options), vec![
+            "--base-font-size", "12",
+            "--smarten-punctuation",
+            "--margin-top", "1.5",
+            "--disable-unwrap-lines",
+            "--chapter", "//h:h1",
+        ]);
+        assert!(calibre_args(&ConversionOptions::new()).is_empty());
+    }
+
+    #[test]
+    fn progress_lines() {
+        assert_eq!(parse_progress("34% Running transforms on e-book..."), Some(34));
+        assert_eq!(parse_progress("  1% Converting input to HTML..."), Some(1));
+        assert_eq!(parse_progress("0%"), Some(0));
+        assert_eq!(parse_progress("100%"), Some(100));
+        assert_eq!(parse_progress("101%"), None);
+        assert_eq!(parse_progress("1000%"), None);
+        assert_eq!(parse_progress("99999999999999999999% overflow"), None);
+        assert_eq!(parse_progress("% done"), None);
+        assert_eq!(parse_progress("34 files"), None);
+        assert_eq!(parse_progress("Output saved to 100%.epub"), None);
+        assert_eq!(parse_progress(""), None);
+    }
+}
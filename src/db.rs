@@ -1,6 +1,8 @@
-use rusqlite::{Connection, OpenFlags};
+use rusqlite::{Connection, OpenFlags, Row};
+use rusqlite::types::{ToSql, Value};
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::collections::BTreeMap;
 
 use rusoto_core::{Region};
 use rusoto_s3::{S3,S3Client,GetObjectRequest,GetObjectError};
@@ -11,6 +13,9 @@ use futures::stream::Stream;
 use futures::Future;
 use std::io::Error;
 use hyper::Uri;
+use serde_json;
+
+use search::Expr;
 
 pub trait DBConnector : Send + Sync {
     fn get_connection(&self) -> Connection;
@@ -124,13 +129,272 @@ impl DBConnector for S3DBConnector {
 }
 
 
-#[derive(Serialize, Deserialize)]
+/// Separator used in `group_concat` so that names containing commas survive.
+const LIST_SEPARATOR: char = '\x1f';
+
+/// Calibre uses this date as a placeholder for unknown publication dates.
+const UNDEFINED_DATE_PREFIX: &str = "0101-01-01";
+
+const BOOK_QUERY: &str = "
+SELECT books.id, books.title, books.author_sort, books.uuid,
+       (SELECT group_concat(data.format, char(31))
+          FROM data WHERE data.book = books.id),
+       (SELECT group_concat(authors.name, char(31))
+          FROM authors INNER JOIN books_authors_link
+            ON books_authors_link.author = authors.id
+         WHERE books_authors_link.book = books.id),
+       (SELECT series.name
+          FROM series INNER JOIN books_series_link
+            ON books_series_link.series = series.id
+         WHERE books_series_link.book = books.id),
+       books.series_index,
+       (SELECT group_concat(tags.name, char(31))
+          FROM tags INNER JOIN books_tags_link
+            ON books_tags_link.tag = tags.id
+         WHERE books_tags_link.book = books.id),
+       (SELECT publishers.name
+          FROM publishers INNER JOIN books_publishers_link
+            ON books_publishers_link.publisher = publishers.id
+         WHERE books_publishers_link.book = books.id),
+       (SELECT group_concat(languages.lang_code, char(31))
+          FROM languages INNER JOIN books_languages_link
+            ON books_languages_link.lang_code = languages.id
+         WHERE books_languages_link.book = books.id),
+       (SELECT ratings.rating
+          FROM ratings INNER JOIN books_ratings_link
+            ON books_ratings_link.rating = ratings.id
+         WHERE books_ratings_link.book = books.id),
+       books.pubdate,
+       (SELECT group_concat(identifiers.type || ':' || identifiers.val, char(31))
+          FROM identifiers WHERE identifiers.book = books.id),
+       (SELECT comments.text FROM comments WHERE comments.book = books.id),
+       books.has_cover, books.path, books.last_modified
+  FROM books
+ WHERE EXISTS (SELECT 1 FROM data WHERE data.book = books.id)";
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Book {
-    id: i64,
-    title: String,
-    author_sort: String,
-    uuid: String,
-    available_data: Vec<String>
+    pub id: i64,
+    pub title: String,
+    pub author_sort: String,
+    pub uuid: String,
+    pub available_data: Vec<String>,
+    pub authors: Vec<String>,
+    pub series: Option<String>,
+    pub series_index: Option<f64>,
+    pub tags: Vec<String>,
+    pub publisher: Option<String>,
+    pub languages: Vec<String>,
+    /// Rating as stored by Calibre, i.e. in half stars from 0 to 10.
+    pub rating: Option<i64>,
+    pub pubdate: Option<String>,
+    pub identifiers: BTreeMap<String, String>,
+    pub comments: Option<String>,
+    pub has_cover: bool,
+    pub last_modified: String,
+    /// Directory of the book relative to the data root.
+    #[serde(skip)]
+    pub path: String,
+}
+
+/// A file of a book in one of its available formats.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Format {
+    pub format: String,
+    pub size: i64,
+}
+
+fn split_list(joined: Option<String>) -> Vec<String> {
+    match joined {
+        Some(s) => s.split(LIST_SEPARATOR).map(|s| s.to_string()).collect(),
+        None => Vec::new()
+    }
+}
+
+impl Book {
+    fn from_row(row: &Row) -> Self {
+        let series: Option<String> = row.get(6);
+        let series_index: Option<f64> = if series.is_some() {
+            row.get(7)
+        } else {
+            None
+        };
+        let pubdate: Option<String> = row.get(12);
+        let pubdate = pubdate.and_then(|d| {
+            if d.starts_with(UNDEFINED_DATE_PREFIX) { None } else { Some(d) }
+        });
+
+        let mut identifiers = BTreeMap::new();
+        for ident in split_list(row.get(13)) {
+            let mut kv = ident.splitn(2, ':');
+            if let (Some(k), Some(v)) = (kv.next(), kv.next()) {
+                identifiers.insert(k.to_string(), v.to_string());
+            }
+        }
+
+        Book {
+            id: row.get(0),
+            title: row.get(1),
+            author_sort: row.get(2),
+            uuid: row.get(3),
+            available_data: split_list(row.get(4)),
+            authors: split_list(row.get(5)),
+            series: series,
+            series_index: series_index,
+            tags: split_list(row.get(8)),
+            publisher: row.get(9),
+            languages: split_list(row.get(10)),
+            rating: row.get(11),
+            pubdate: pubdate,
+            identifiers: identifiers,
+            comments: row.get(14),
+            has_cover: row.get(15),
+            path: row.get(16),
+            last_modified: row.get(17),
+        }
+    }
+}
+
+/// Fields the book list can be ordered by.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SortKey {
+    Id,
+    Title,
+    AuthorSort,
+    Series,
+    Pubdate,
+    Timestamp,
+    Rating,
+}
+
+impl FromStr for SortKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "id" => Ok(SortKey::Id),
+            "title" => Ok(SortKey::Title),
+            "author_sort" => Ok(SortKey::AuthorSort),
+            "series" => Ok(SortKey::Series),
+            "pubdate" => Ok(SortKey::Pubdate),
+            "timestamp" => Ok(SortKey::Timestamp),
+            "rating" => Ok(SortKey::Rating),
+            _ => Err(format!("Unknown sort key: {}", s))
+        }
+    }
+}
+
+impl SortKey {
+    fn order_by(&self, descending: bool) -> String {
+        let dir = if descending { "DESC" } else { "ASC" };
+        match *self {
+            SortKey::Id => format!("books.id {}", dir),
+            SortKey::Title =>
+                format!("books.sort COLLATE NOCASE {}, books.id", dir),
+            SortKey::AuthorSort =>
+                format!("books.author_sort COLLATE NOCASE {}, books.sort", dir),
+            SortKey::Series => format!("
+(SELECT series.sort FROM series INNER JOIN books_series_link
+    ON books_series_link.series = series.id
+ WHERE books_series_link.book = books.id) COLLATE NOCASE {0},
+books.series_index {0}, books.id", dir),
+            SortKey::Pubdate => format!("books.pubdate {}, books.id", dir),
+            SortKey::Timestamp => format!("books.timestamp {}, books.id", dir),
+            SortKey::Rating => format!("
+(SELECT ratings.rating FROM ratings INNER JOIN books_ratings_link
+    ON books_ratings_link.rating = ratings.id
+ WHERE books_ratings_link.book = books.id) {}, books.id", dir),
+        }
+    }
+}
+
+/// Kinds of entities books are grouped by in catalog navigation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Category {
+    Author,
+    Series,
+    Tag,
+}
+
+impl Category {
+    /// (entity table, link table, link column, sort column)
+    fn tables(&self) -> (&'static str, &'static str, &'static str, &'static str) {
+        match *self {
+            Category::Author => ("authors", "books_authors_link", "author", "sort"),
+            Category::Series => ("series", "books_series_link", "series", "sort"),
+            Category::Tag => ("tags", "books_tags_link", "tag", "name"),
+        }
+    }
+}
+
+/// An author, series or tag together with the number of its books.
+pub struct CategoryItem {
+    pub id: i64,
+    pub name: String,
+    pub count: i64,
+}
+
+/// A window of the book list, ordered and optionally filtered.
+pub struct BookQuery {
+    pub offset: i64,
+    pub limit: Option<i64>,
+    pub sort: SortKey,
+    pub descending: bool,
+    pub filter: Option<Expr>,
+    /// Restricts the list to books linked to the given author, series or tag.
+    pub category: Option<(Category, i64)>,
+}
+
+impl Default for BookQuery {
+    fn default() -> Self {
+        BookQuery {
+            offset: 0,
+            limit: None,
+            sort: SortKey::Title,
+            descending: false,
+            filter: None,
+            category: None,
+        }
+    }
+}
+
+impl BookQuery {
+    fn where_clause(&self, params: &mut Vec<Value>) -> String {
+        let mut clause = match self.filter {
+            Some(ref filter) => format!(" AND {}", filter.to_sql(params)),
+            None => String::new()
+        };
+        if let Some((category, id)) = self.category {
+            let (_, link_table, link_column, _) = category.tables();
+            params.push(Value::Integer(id));
+            clause.push_str(&format!(
+                " AND EXISTS (SELECT 1 FROM {0} WHERE {0}.book = books.id AND {0}.{1} = ?{2})",
+                link_table, link_column, params.len()));
+        }
+        clause
+    }
+}
+
+/// Per-book conversion options as stored by Calibre, keyed by option names
+/// such as "base_font_size".
+pub type ConversionOptions = BTreeMap<String, serde_json::Value>;
+
+/// Calibre stores the options as JSON wrapped in a protocol 2 pickle of a
+/// byte string. Older libraries may contain pickled dicts, which are ignored.
+fn decode_conversion_options(blob: &[u8]) -> Option<ConversionOptions> {
+    if blob.len() < 3 || &blob[..2] != b"\x80\x02" {
+        return None;
+    }
+    let (start, len) = match blob[2] {
+        // SHORT_BINSTRING and SHORT_BINBYTES have a 1-byte length
+        b'U' | b'C' if blob.len() >= 4 => (4, blob[3] as usize),
+        // BINSTRING and BINBYTES have a 4-byte little-endian length
+        b'T' | b'B' if blob.len() >= 7 =>
+            (7, blob[3..7].iter().rev().fold(0, |acc, b| acc << 8 | *b as usize)),
+        _ => return None
+    };
+    blob.get(start..start + len)
+        .and_then(|json| serde_json::from_slice(json).ok())
 }
 
 pub struct BookList<'a> {
@@ -146,24 +410,179 @@ impl<'a> BookList<'a> {
 
     pub fn for_each<F>(&self, f: F) where F: FnMut(&Book) {
         let mut f = f;
-        let mut stmt = self.conn.prepare("
-SELECT books.id, title, author_sort, uuid, group_concat(data.format)
-  FROM books
-  INNER JOIN data WHERE data.book = books.id GROUP BY books.id").unwrap();
+        let mut stmt = self.conn.prepare(BOOK_QUERY).unwrap();
         let mut rows = stmt.query(&[]).unwrap();
         while let Some(result_row) = rows.next() {
             let row = result_row.unwrap();
+            f(&Book::from_row(&row))
+        }
+    }
+
+    /// Number of books in the library.
+    pub fn count_all(&self) -> i64 {
+        self.count(&BookQuery::default())
+    }
+
+    /// Number of books matching the filter of `query`.
+    pub fn count(&self, query: &BookQuery) -> i64 {
+        let mut values = Vec::new();
+        let sql = format!("
+SELECT count(*) FROM books
+ WHERE EXISTS (SELECT 1 FROM data WHERE data.book = books.id){}",
+                          query.where_clause(&mut values));
+        let params: Vec<&ToSql> = values.iter().map(|v| v as &ToSql).collect();
+        self.conn.query_row(&sql, &params, |row| row.get(0)).unwrap()
+    }
+
+    /// Calls `f` for each book in the window specified by `query`.
+    pub fn for_each_in<F>(&self, query: &BookQuery, f: F) where F: FnMut(&Book) {
+        let mut f = f;
+        let mut values = Vec::new();
+        let mut sql = format!("{}{} ORDER BY {}",
+                              BOOK_QUERY,
+                              query.where_clause(&mut values),
+                              query.sort.order_by(query.descending));
+        values.push(Value::Integer(query.limit.unwrap_or(-1)));
+        values.push(Value::Integer(query.offset));
+        sql.push_str(&format!(" LIMIT ?{} OFFSET ?{}",
+                              values.len() - 1, values.len()));
+
+        let params: Vec<&ToSql> = values.iter().map(|v| v as &ToSql).collect();
+        let mut stmt = self.conn.prepare(&sql).unwrap();
+        let mut rows = stmt.query(&params).unwrap();
+        while let Some(result_row) = rows.next() {
+            let row = result_row.unwrap();
+            f(&Book::from_row(&row))
+        }
+    }
+
+    pub fn get(&self, bookid: i64) -> Option<Book> {
+        let sql = format!("{} AND books.id = (:bookid)", BOOK_QUERY);
+        let mut stmt = self.conn.prepare(&sql).unwrap();
+        let mut rows = stmt.query_named(&[(":bookid", &bookid)]).unwrap();
+        rows.next().map(|row| Book::from_row(&row.unwrap()))
+    }
 
-            let formats: String = row.get(4);
-            let book = Book {
+    /// Authors, series or tags that have at least one book, in sort order.
+    pub fn categories(&self, category: Category, offset: i64, limit: i64)
+                      -> Vec<CategoryItem> {
+        let (table, link_table, link_column, sort_column) = category.tables();
+        let mut stmt = self.conn.prepare(&format!("
+SELECT {0}.id, {0}.name, count({1}.book) FROM {0}
+ INNER JOIN {1} ON {1}.{2} = {0}.id
+ GROUP BY {0}.id ORDER BY {0}.{3} COLLATE NOCASE
+ LIMIT (:limit) OFFSET (:offset)", table, link_table, link_column, sort_column)).unwrap();
+        let mut rows = stmt.query_named(&[
+            (":limit", &limit),
+            (":offset", &offset)
+        ]).unwrap();
+        let mut items = Vec::new();
+        while let Some(result_row) = rows.next() {
+            let row = result_row.unwrap();
+            items.push(CategoryItem {
                 id: row.get(0),
-                title: row.get(1),
-                author_sort: row.get(2),
-                uuid: row.get(3),
-                available_data: formats.split(',').map(
-                   |s| s.to_string()).collect()
-            };
-            f(&book)
+                name: row.get(1),
+                count: row.get(2),
+            });
         }
+        items
+    }
+
+    pub fn count_categories(&self, category: Category) -> i64 {
+        let (table, link_table, link_column, _) = category.tables();
+        self.conn.query_row(&format!("
+SELECT count(DISTINCT {0}.id) FROM {0} INNER JOIN {1} ON {1}.{2} = {0}.id",
+                                     table, link_table, link_column),
+                            &[], |row| row.get(0)).unwrap()
+    }
+
+    pub fn category_name(&self, category: Category, id: i64) -> Option<String> {
+        let (table, _, _, _) = category.tables();
+        self.conn.query_row(&format!("SELECT name FROM {} WHERE id = ?", table),
+                            &[&id], |row| row.get(0)).ok()
+    }
+
+    /// Conversion options the librarian set for the book in Calibre, or an
+    /// empty set if there are none or they can't be decoded.
+    pub fn conversion_options(&self, bookid: i64) -> ConversionOptions {
+        let blob: Option<Vec<u8>> = self.conn.query_row(
+            "SELECT data FROM conversion_options WHERE book = ? AND format = 'PIPE'",
+            &[&bookid], |row| row.get_checked(0)).ok().and_then(|blob| blob.ok());
+        blob.and_then(|blob| {
+            let options = decode_conversion_options(&blob);
+            if options.is_none() {
+                warn!("Ignoring undecodable conversion options of book {}", bookid);
+            }
+            options
+        }).unwrap_or_default()
+    }
+
+    /// Formats of the book together with their file sizes in bytes.
+    pub fn formats(&self, bookid: i64) -> Vec<Format> {
+        let mut stmt = self.conn.prepare("
+SELECT data.format, data.uncompressed_size FROM data
+ WHERE data.book = (:bookid) ORDER BY data.format").unwrap();
+        let mut rows = stmt.query_named(&[(":bookid", &bookid)]).unwrap();
+        let mut formats = Vec::new();
+        while let Some(result_row) = rows.next() {
+            let row = result_row.unwrap();
+            formats.push(Format {
+                format: row.get(0),
+                size: row.get(1),
+            });
+        }
+        formats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pickle(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut blob = vec![0x80, 0x02, opcode];
+        match opcode {
+            b'U' | b'C' => blob.push(payload.len() as u8),
+            _ => {
+                let len = payload.len() as u32;
+                blob.extend_from_slice(&[len as u8, (len >> 8) as u8,
+                                         (len >> 16) as u8, (len >> 24) as u8]);
+            }
+        }
+        blob.extend_from_slice(payload);
+        blob.extend_from_slice(b"q\x00.");
+        blob
+    }
+
+    #[test]
+    fn decode_options() {
+        let json = br#"{"base_font_size": 12, "no_inline_toc": true}"#;
+        for opcode in b"UCTB" {
+            let options = decode_conversion_options(&pickle(*opcode, json)).unwrap();
+            assert_eq!(options.len(), 2);
+            assert_eq!(options["base_font_size"].as_i64(), Some(12));
+            assert_eq!(options["no_inline_toc"].as_bool(), Some(true));
+        }
+        let long = format!(r#"{{"extra_css": "{}"}}"#, "x".repeat(300));
+        let options = decode_conversion_options(&pickle(b'T', long.as_bytes())).unwrap();
+        assert_eq!(options["extra_css"].as_str().unwrap().len(), 300);
+    }
+
+    #[test]
+    fn decode_malformed_options() {
+        assert_eq!(decode_conversion_options(b""), None);
+        assert_eq!(decode_conversion_options(b"\x80\x02"), None);
+        // Wrong protocol
+        assert_eq!(decode_conversion_options(b"\x80\x03U\x02{}"), None);
+        // Pickled dict
+        assert_eq!(decode_conversion_options(b"\x80\x02}q\x00."), None);
+        // Truncated lengths and payloads
+        assert_eq!(decode_conversion_options(b"\x80\x02U"), None);
+        assert_eq!(decode_conversion_options(b"\x80\x02T\x02\x00\x00"), None);
+        assert_eq!(decode_conversion_options(b"\x80\x02U\x10{}"), None);
+        assert_eq!(decode_conversion_options(b"\x80\x02T\xff\xff\xff\xff{}"), None);
+        // Not JSON, or JSON other than an object
+        assert_eq!(decode_conversion_options(&pickle(b'U', b"{'a': 1}")), None);
+        assert_eq!(decode_conversion_options(&pickle(b'U', b"[1, 2]")), None);
     }
 }
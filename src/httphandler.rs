@@ -1,27 +1,47 @@
-use std::path::PathBuf;
-use std::sync::mpsc::SyncSender;
+use std::io;
+use std::io::Read;
+use std::fs::File;
+use std::path::{Component, Path, PathBuf};
 use std::sync::Arc;
 
 use actix_web::{HttpRequest, Responder, fs, HttpResponse,
                 Either as EitherResponder};
+use actix_web::error::ErrorInternalServerError;
 use actix_web::http::StatusCode;
 use actix_web::http::header::{ContentDisposition, DispositionType,
                               DispositionParam, Charset,
                               ContentEncoding};
+use ammonia;
 use askama::Template;
+use bytes::Bytes;
+use futures::Stream;
 use serde_json;
 use rusqlite::{Connection};
 
-use db::{BookList,DBConnector};
-use cache::check_cache_availability;
+use db::{Book,BookList,BookQuery,Format,DBConnector};
+use cache::{check_cache_freshness, get_thumbnail, CacheManager, SourceFingerprint};
+use comic::{find_comic_source, ComicCache, ComicError, Extractors};
+use search;
+use jobs::{Job, JobRegistry, JobState};
+use store::{ConversionFailure, Store};
+use worker::{find_sources, ConversionTask, Priority, TaskQueue, WorkerHealth,
+             WorkerStatus};
 
 pub struct AppConfig {
     pub db_connector: Box<DBConnector>,
     pub static_path: PathBuf,
     pub cache_path: PathBuf,
+    pub cache: CacheManager,
     pub data_path: PathBuf,
     pub app_prefix: String,
-    pub conv_task_tx: SyncSender<(PathBuf, PathBuf)>
+    pub queue: TaskQueue,
+    pub jobs: JobRegistry,
+    pub store: Store,
+    pub workers: WorkerHealth,
+    pub extractors: Extractors,
+    /// Whether pdf.js is installed so that PDFs can be read without
+    /// converting them
+    pub pdf_viewer: bool,
 }
 
 impl AppConfig {
@@ -30,10 +50,33 @@ impl AppConfig {
     }
 }
 
-pub type AppState = Arc<AppConfig>;
+/// Scripts of pdf.js the PDF reader page loads from the static dir
+const PDF_VIEWER_FILES: &[&str] = &["js/pdf.min.js", "js/pdf.worker.min.js"];
+
+/// Tells if pdf.js is installed in the static dir.
+pub fn has_pdf_viewer(static_path: &Path) -> bool {
+    PDF_VIEWER_FILES.iter().all(|file| static_path.join(file).is_file())
+}
 
-/// List of all supported formats in the preference order
-const PREFERRED_FORMAT: &[&'static str] = &["EPUB", "HTMLZ", "AZW3", "AZW4", "MOBI", "PDF"];
+/// Picks the reader that reads the book without converting it, if any, as
+/// the route of the reader and the format of the source it reads.
+///
+/// Converting comics and PDFs is slow and mangles their layout, so they're
+/// read as they are; PDFs only unless there's a better format, and only if
+/// pdf.js is installed.
+pub fn native_reader(sources: &[SourceFingerprint], pdf_viewer: bool)
+                     -> Option<(&'static str, String)> {
+    if let Some(source) = find_comic_source(sources) {
+        Some(("comic", source.format()))
+    } else if pdf_viewer && !sources.is_empty()
+        && sources.iter().all(|source| source.format() == "PDF") {
+        Some(("pdfreader", "PDF".to_string()))
+    } else {
+        None
+    }
+}
+
+pub type AppState = Arc<AppConfig>;
 
 #[derive(Template)]
 #[template(path = "main_page.html", escape = "none")]
@@ -41,6 +84,15 @@ struct MainPage<'a> {
     app_prefix: &'a str,
 }
 
+/// File name of the cover image in each book directory
+const COVER_FILE: &str = "cover.jpg";
+
+/// Allowed thumbnail sizes; restricted to keep the thumbnail cache bounded
+const THUMBNAIL_SIZES: &[u32] = &[64, 128, 256, 512];
+
+/// Thumbnail size used in the book list
+const LIST_THUMBNAIL_SIZE: u32 = 64;
+
 #[derive(Template)]
 #[template(path = "reader_page.html", escape = "none")]
 struct ReaderPage<'a> {
@@ -48,6 +100,50 @@ struct ReaderPage<'a> {
     bookid: i64,
 }
 
+#[derive(Template)]
+#[template(path = "pdf_reader_page.html", escape = "none")]
+struct PdfReaderPage<'a> {
+    app_prefix: &'a str,
+    bookid: i64,
+}
+
+#[derive(Template)]
+#[template(path = "comic_reader_page.html", escape = "none")]
+struct ComicReaderPage<'a> {
+    app_prefix: &'a str,
+    bookid: i64,
+    /// Initial page direction; readers can flip it
+    rtl: bool,
+}
+
+struct FormatLink {
+    format: String,
+    size: String,
+    uri: String,
+}
+
+struct IdentifierEntry {
+    name: String,
+    value: String,
+}
+
+#[derive(Template)]
+#[template(path = "book_page.html")]
+struct BookPage<'a> {
+    app_prefix: &'a str,
+    book: &'a Book,
+    authors: String,
+    series: String,
+    tags: String,
+    languages: String,
+    publisher: String,
+    pubdate: String,
+    rating: String,
+    comments: String,
+    identifiers: Vec<IdentifierEntry>,
+    formats: Vec<FormatLink>,
+}
+
 pub fn get_main_page(req: &HttpRequest<AppState>) -> HttpResponse {
     HttpResponse::Ok()
         .content_type("text/html")
@@ -60,6 +156,7 @@ pub fn get_main_page(req: &HttpRequest<AppState>) -> HttpResponse {
 pub fn get_reader_page(req: &HttpRequest<AppState>) -> HttpResponse {
     let bookid: i64 =
         req.match_info().get("bookid").unwrap().parse().unwrap();
+    req.state().cache.touch(bookid);
     HttpResponse::Ok()
         .content_type("text/html")
         .body(ReaderPage {
@@ -68,23 +165,263 @@ pub fn get_reader_page(req: &HttpRequest<AppState>) -> HttpResponse {
         }.render().unwrap())
 }
 
-pub fn get_book_list(req: &HttpRequest<AppState>) -> impl Responder {
+pub fn get_pdf_reader_page(req: &HttpRequest<AppState>) -> HttpResponse {
+    let bookid: i64 =
+        req.match_info().get("bookid").unwrap().parse().unwrap();
+    if !req.state().pdf_viewer {
+        return HttpResponse::new(StatusCode::NOT_FOUND);
+    }
+    HttpResponse::Ok()
+        .content_type("text/html")
+        .body(PdfReaderPage {
+            app_prefix: &req.state().app_prefix,
+            bookid: bookid,
+        }.render().unwrap())
+}
+
+pub fn get_comic_reader_page(req: &HttpRequest<AppState>) -> HttpResponse {
+    let bookid: i64 =
+        req.match_info().get("bookid").unwrap().parse().unwrap();
     let conn = req.state().get_meta_data_conn();
-    let booklist = BookList::new(&conn);
+    let book = match BookList::new(&conn).get(bookid) {
+        Some(book) => book,
+        None => return HttpResponse::new(StatusCode::NOT_FOUND)
+    };
+    // Calibre has no reading direction for comics, so guess it from tags.
+    let rtl = book.tags.iter().any(|tag| tag.to_lowercase() == "manga");
+    req.state().cache.touch_comic(bookid);
+    HttpResponse::Ok()
+        .content_type("text/html")
+        .body(ComicReaderPage {
+            app_prefix: &req.state().app_prefix,
+            bookid: bookid,
+            rtl: rtl,
+        }.render().unwrap())
+}
 
-    let mut list = String::new();
-    list.push_str("[");
-    let mut is_first = true;
-    booklist.for_each(|ref book| {
-        if is_first {
-            is_first = false;
-        } else {
-            list.push_str(",");
+#[derive(Serialize)]
+struct BookListEntry {
+    #[serde(flatten)]
+    book: Book,
+    thumbnail: Option<String>,
+}
+
+#[derive(Serialize)]
+struct BookListResponse {
+    draw: Option<i64>,
+    total: i64,
+    filtered: i64,
+    books: Vec<BookListEntry>,
+}
+
+fn parse_book_query(req: &HttpRequest<AppState>) -> Result<BookQuery, String> {
+    let params = req.query();
+    let mut query = BookQuery::default();
+
+    if let Some(offset) = params.get("offset") {
+        query.offset = try!(offset.parse().map_err(
+            |_| format!("Invalid offset: {}", offset)));
+    }
+    if let Some(limit) = params.get("limit") {
+        let limit: i64 = try!(limit.parse().map_err(
+            |_| format!("Invalid limit: {}", limit)));
+        // DataTables requests -1 for "all rows"
+        query.limit = if limit < 0 { None } else { Some(limit) };
+    }
+    if let Some(sort) = params.get("sort") {
+        query.sort = try!(sort.parse());
+    }
+    if let Some(order) = params.get("order") {
+        query.descending = match order.as_str() {
+            "asc" => false,
+            "desc" => true,
+            _ => return Err(format!("Invalid order: {}", order))
+        };
+    }
+    if let Some(filter) = params.get("filter") {
+        if !filter.trim().is_empty() {
+            query.filter = Some(try!(search::parse(filter).map_err(
+                |e| e.to_string())));
+        }
+    }
+    Ok(query)
+}
+
+pub fn get_book_list(req: &HttpRequest<AppState>) -> HttpResponse {
+    let query = match parse_book_query(req) {
+        Ok(query) => query,
+        Err(msg) => {
+            return HttpResponse::BadRequest().body(msg);
         }
-        list.push_str(&serde_json::to_string(&book).unwrap());
+    };
+    let draw = req.query().get("draw").and_then(|s| s.parse().ok());
+
+    let conn = req.state().get_meta_data_conn();
+    let booklist = BookList::new(&conn);
+
+    let app_prefix = &req.state().app_prefix;
+    let mut books = Vec::new();
+    booklist.for_each_in(&query, |book| {
+        books.push(BookListEntry {
+            thumbnail: thumbnail_uri(app_prefix, book, LIST_THUMBNAIL_SIZE),
+            book: book.clone(),
+        });
     });
-    list.push_str("]");
-    list
+
+    let response = BookListResponse {
+        draw: draw,
+        total: booklist.count_all(),
+        filtered: booklist.count(&query),
+        books: books,
+    };
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(serde_json::to_string(&response).unwrap())
+}
+
+#[derive(Serialize)]
+struct BookDetail {
+    #[serde(flatten)]
+    book: Book,
+    formats: Vec<Format>,
+}
+
+pub fn get_book_metadata(req: &HttpRequest<AppState>) -> impl Responder {
+    let bookid: i64 =
+        req.match_info().get("bookid").unwrap().parse().unwrap();
+    let conn = req.state().get_meta_data_conn();
+    let booklist = BookList::new(&conn);
+
+    match booklist.get(bookid) {
+        Some(book) => {
+            let detail = BookDetail {
+                book: book,
+                formats: booklist.formats(bookid),
+            };
+            EitherResponder::A(serde_json::to_string(&detail).unwrap())
+        },
+        None => EitherResponder::B(HttpResponse::new(StatusCode::NOT_FOUND))
+    }
+}
+
+fn format_file_size(size: i64) -> String {
+    const UNITS: &[&str] = &["KB", "MB", "GB"];
+    if size < 1024 {
+        return format!("{} B", size);
+    }
+    let mut size = size as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+pub fn get_book_page(req: &HttpRequest<AppState>) -> HttpResponse {
+    let bookid: i64 =
+        req.match_info().get("bookid").unwrap().parse().unwrap();
+    let conn = req.state().get_meta_data_conn();
+    let booklist = BookList::new(&conn);
+    let app_prefix = &req.state().app_prefix;
+
+    let book = match booklist.get(bookid) {
+        Some(book) => book,
+        None => return HttpResponse::new(StatusCode::NOT_FOUND)
+    };
+    let formats = booklist.formats(bookid).into_iter().map(|f| {
+        FormatLink {
+            uri: format!("{}/data/{}/{}", app_prefix, bookid, f.format),
+            size: format_file_size(f.size),
+            format: f.format,
+        }
+    }).collect();
+    let identifiers = book.identifiers.iter().map(|(k, v)| {
+        IdentifierEntry { name: k.clone(), value: v.clone() }
+    }).collect();
+    let series = match book.series {
+        Some(ref name) =>
+            format!("Book {} of {}", book.series_index.unwrap_or(1.0), name),
+        None => String::new()
+    };
+
+    HttpResponse::Ok()
+        .content_type("text/html")
+        .body(BookPage {
+            app_prefix: app_prefix,
+            book: &book,
+            authors: book.authors.join(" & "),
+            series: series,
+            tags: book.tags.join(", "),
+            languages: book.languages.join(", "),
+            publisher: book.publisher.clone().unwrap_or_default(),
+            pubdate: book.pubdate.as_ref()
+                .map(|d| d.chars().take(10).collect()).unwrap_or_default(),
+            rating: book.rating.map(|r| "\u{2605}".repeat((r / 2) as usize))
+                .unwrap_or_default(),
+            // Comments are HTML anyone editing metadata can write.
+            comments: book.comments.as_ref()
+                .map(|c| ammonia::clean(c)).unwrap_or_default(),
+            identifiers: identifiers,
+            formats: formats,
+        }.render().unwrap())
+}
+
+/// Returns the URI of a `size`px thumbnail of the cover of `book`.
+pub fn thumbnail_uri(app_prefix: &str, book: &Book, size: u32) -> Option<String> {
+    if book.has_cover {
+        Some(format!("{}/cover/{}?size={}&v={}",
+                     app_prefix, book.id, size,
+                     book.last_modified.replace(|c: char| !c.is_ascii_digit(), "")))
+    } else {
+        None
+    }
+}
+
+pub fn get_book_cover(req: &HttpRequest<AppState>) -> impl Responder {
+    let bookid: i64 =
+        req.match_info().get("bookid").unwrap().parse().unwrap();
+    let size: Option<u32> = match req.query().get("size") {
+        Some(s) => match s.parse() {
+            Ok(size) if THUMBNAIL_SIZES.contains(&size) => Some(size),
+            _ => return EitherResponder::B(
+                HttpResponse::BadRequest().body(
+                    format!("Thumbnail size must be one of {:?}", THUMBNAIL_SIZES)))
+        },
+        None => None
+    };
+    let conn = req.state().get_meta_data_conn();
+    let booklist = BookList::new(&conn);
+
+    let book = match booklist.get(bookid) {
+        Some(ref book) if book.has_cover => book.clone(),
+        _ => return EitherResponder::B(HttpResponse::new(StatusCode::NOT_FOUND))
+    };
+
+    let mut cover_path = req.state().data_path.clone();
+    cover_path.push(&book.path);
+    cover_path.push(COVER_FILE);
+
+    let image_path = match size {
+        Some(size) => match get_thumbnail(&req.state().cache_path, &cover_path,
+                                          bookid, size, &book.last_modified) {
+            Ok(path) => path,
+            Err(e) => {
+                warn!("Failed to make thumbnail of book {}: {}", bookid, e);
+                return EitherResponder::B(
+                    HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR));
+            }
+        },
+        None => cover_path
+    };
+
+    match fs::NamedFile::open(image_path) {
+        Ok(file) => EitherResponder::A(file),
+        Err(e) => {
+            warn!("Failed to open cover of book {}: {}", bookid, e);
+            EitherResponder::B(HttpResponse::new(StatusCode::NOT_FOUND))
+        }
+    }
 }
 
 pub fn get_book_data(req: &HttpRequest<AppState>) -> impl Responder {
@@ -144,10 +481,67 @@ SELECT books.title, books.path, data.name FROM books
     resp
 }
 
-pub fn get_reader_status(req: &HttpRequest<AppState>) -> impl Responder {
+/// Serves a file of an extracted book. Only the numbered book dirs are
+/// served; the rest of the cache dir, e.g. the store and conversion logs, is
+/// internal.
+/// Tells if a path in an extracted book may be served. Hidden files are the
+/// cache's own bookkeeping, e.g. `.source.json`, and the path mustn't leave
+/// the book.
+fn is_public_book_path(relpath: &Path) -> bool {
+    relpath.components().all(|component| match component {
+        Component::Normal(name) => !name.to_string_lossy().starts_with('.'),
+        _ => false
+    })
+}
+
+pub fn get_cached_book_file(req: &HttpRequest<AppState>) -> impl Responder {
+    let bookid: i64 = match req.match_info().get("bookid").unwrap().parse() {
+        Ok(bookid) => bookid,
+        Err(_) => return EitherResponder::B(HttpResponse::new(StatusCode::NOT_FOUND))
+    };
+    let relpath = PathBuf::from(req.match_info().get("path").unwrap_or(""));
+    let is_safe = is_public_book_path(&relpath);
+    let mut path = req.state().cache_path.clone();
+    path.push(format!("{}", bookid));
+    path.push(relpath);
+    if !is_safe || !path.is_file() {
+        return EitherResponder::B(HttpResponse::new(StatusCode::NOT_FOUND));
+    }
+    match fs::NamedFile::open(path) {
+        Ok(file) => EitherResponder::A(file),
+        Err(e) => {
+            warn!("Failed to open a file of book {}: {}", bookid, e);
+            EitherResponder::B(HttpResponse::new(StatusCode::NOT_FOUND))
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ReaderStatus {
+    is_ready: bool,
+    uri: String,
+    /// Format of the source file the cached copy was made from
+    source_format: Option<String>,
+    /// Set if the book can't be previewed; it won't be converted again
+    /// until its source changes or the failure is cleared
+    failure: Option<ConversionFailure>,
+    /// Latest conversion job of the book, if any
+    job: Option<Job>,
+    /// Lets the client cancel the job; only sent to the request that
+    /// queued it
+    cancel_token: Option<String>,
+}
+
+pub fn get_reader_status(req: &HttpRequest<AppState>) -> HttpResponse {
     let do_enqueue =
         req.query().get("enqueue").and_then(|s| s.parse().ok()).unwrap_or(1)
         != 0;
+    // Clients prefetching books nobody is waiting for can ask to be served
+    // after interactive requests.
+    let priority = match req.query().get("priority").map(|s| s.as_str()) {
+        Some("background") => Priority::Background,
+        _ => Priority::Interactive
+    };
     let conn = req.state().get_meta_data_conn();
     let bookid: i64 =
         req.match_info().get("bookid").unwrap().parse().unwrap();
@@ -162,58 +556,292 @@ pub fn get_reader_status(req: &HttpRequest<AppState>) -> impl Responder {
     reader_uri.push_str("/reader/");
     reader_uri.push_str(&format!("{}", bookid));
 
-    let mut is_ready = "true";
-    if ! check_cache_availability(&reader_path) {
-        if do_enqueue {
-            let mut stmt = conn.prepare("
-SELECT books.path, data.name, data.format
-FROM books INNER JOIN data
-WHERE data.book = books.id AND books.id = (:bookid)").unwrap();
-
-            let mut rows = stmt.query_named(&[(":bookid", &bookid)]).unwrap();
-
-            // Really need refactoring
-            let mut dirname: String = String::new();
-            let mut filename: String = String::new();
-            let mut min_cost = PREFERRED_FORMAT.len() + 1;
-            let mut min_format = String::new();
-
-            while let Some(result_row) = rows.next() {
-                let row = result_row.unwrap();
-                let format: String = row.get(2);
-                let cost =
-                    PREFERRED_FORMAT.iter().position(|x| *x == format)
-                    .unwrap_or(PREFERRED_FORMAT.len());
-                if cost < min_cost {
-                    min_cost = cost;
-                    min_format = format;
-                    dirname = row.get(0);
-                    filename = row.get(1);
-                }
-            }
+    let sources = find_sources(&conn, &req.state().data_path, bookid);
+    if sources.is_empty() {
+        return HttpResponse::new(StatusCode::NOT_FOUND);
+    }
 
-            filename.push('.');
-            filename.push_str(&min_format.to_lowercase());
-
-            let mut src_path = req.state().data_path.clone();
-            src_path.push(dirname);
-            src_path.push(filename);
-
-            match req.state().conv_task_tx.send((src_path, reader_path)) {
-                Ok(_) => {
-                    debug!("Status checked, and enqueued the task");
-                },
-                Err(_) => {
-                    warn!("Enqueuing failed")
+    if let Some((route, format)) = native_reader(&sources, req.state().pdf_viewer) {
+        return HttpResponse::Ok()
+            .content_type("application/json")
+            .body(serde_json::to_string(&ReaderStatus {
+                is_ready: true,
+                uri: format!("{}/{}/{}", req.state().app_prefix, route, bookid),
+                source_format: Some(format),
+                failure: None,
+                job: None,
+                cancel_token: None,
+            }).unwrap());
+    }
+
+    let cached_source = check_cache_freshness(&reader_path, &sources);
+    let is_ready = cached_source.is_some();
+    let mut cancel_token = None;
+    let failure = if is_ready {
+        None
+    } else {
+        req.state().store.get_failure(bookid, &sources).unwrap_or_else(|e| {
+            warn!("Failed to look up the conversion failure of book {}: {}", bookid, e);
+            None
+        })
+    };
+    if is_ready {
+        req.state().cache.touch(bookid);
+    } else if failure.is_some() {
+        debug!("Status checked, but the book is known to fail conversion");
+    } else if ! do_enqueue {
+        debug!("Status checked, but didn't enqueue the task");
+    } else {
+        // The task is made before registering the job, since a job left in
+        // flight without a task would block the book forever.
+        let mut task = ConversionTask {
+            bookid: bookid,
+            dest: reader_path,
+            sources: sources,
+            options: BookList::new(&conn).conversion_options(bookid),
+            priority: priority,
+            generation: 0,
+        };
+        match req.state().jobs.try_queue(bookid) {
+            Some(generation) => {
+                task.generation = generation;
+                // Persisted only once accepted, so that a rejected task is
+                // never resumed. If a worker finishes it before it's saved,
+                // the stale row is dropped on resume since the book is fresh.
+                let queued = task.clone();
+                if req.state().queue.try_push(task).is_err() {
+                    warn!("Conversion queue is full; rejecting book {}", bookid);
+                    req.state().jobs.forget(bookid);
+                    return HttpResponse::ServiceUnavailable()
+                        .header("Retry-After", "30")
+                        .body("Conversion queue is full");
+                }
+                if let Err(e) = req.state().store.save_task(&queued) {
+                    warn!("Failed to persist the task of book {}: {}", bookid, e);
                 }
+                cancel_token = req.state().jobs.get(bookid)
+                    .and_then(|job| if job.generation == generation {
+                        job.cancel_token
+                    } else {
+                        None
+                    });
+                debug!("Status checked, and enqueued the task");
+            },
+            None => {
+                debug!("Status checked, and attached to the existing job");
+                req.state().queue.promote(bookid, priority);
             }
-        } else {
-            debug!("Status checked, but didn't enqueue the task");
         }
+    }
+
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(serde_json::to_string(&ReaderStatus {
+            is_ready: is_ready,
+            uri: reader_uri,
+            source_format: cached_source.map(|source| source.format()),
+            failure: failure,
+            job: req.state().jobs.get(bookid),
+            cancel_token: cancel_token,
+        }).unwrap())
+}
+
+/// Streams the job updates of the book as Server-Sent Events named after
+/// the job states, e.g. "running", or "progress" for progress updates of a
+/// running job, until the job is finished.
+pub fn get_job_events(req: &HttpRequest<AppState>) -> HttpResponse {
+    let bookid: i64 =
+        req.match_info().get("bookid").unwrap().parse().unwrap();
+    let updates = match req.state().jobs.subscribe(bookid) {
+        Some(updates) => updates,
+        None => return HttpResponse::new(StatusCode::NOT_FOUND)
+    };
+    let events = updates
+        .map(|job| {
+            let event = if job.state == JobState::Running && job.progress.is_some() {
+                "progress"
+            } else {
+                job.state.name()
+            };
+            Bytes::from(format!("event: {}\ndata: {}\n\n", event,
+                                serde_json::to_string(&job).unwrap()))
+        })
+        .map_err(|_| ErrorInternalServerError("Job update stream failed"));
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .header("Cache-Control", "no-cache")
+        // Compression would buffer the events
+        .content_encoding(ContentEncoding::Identity)
+        .streaming(events)
+}
+
+/// Log of the latest conversion of the book, including the output of the
+/// converter.
+pub fn get_conversion_log(req: &HttpRequest<AppState>) -> HttpResponse {
+    let bookid: i64 =
+        req.match_info().get("bookid").unwrap().parse().unwrap();
+    let mut log = Vec::new();
+    match File::open(req.state().cache.log_path(bookid))
+        .and_then(|mut file| file.read_to_end(&mut log)) {
+        Ok(_) => HttpResponse::Ok()
+            .content_type("text/plain; charset=utf-8")
+            .body(log),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound =>
+            HttpResponse::new(StatusCode::NOT_FOUND),
+        Err(e) => {
+            warn!("Failed to read the conversion log of book {}: {}", bookid, e);
+            HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct WorkersResponse {
+    /// Number of tasks waiting for a worker
+    queued: usize,
+    workers: Vec<WorkerStatus>,
+}
+
+pub fn get_worker_status(req: &HttpRequest<AppState>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(serde_json::to_string(&WorkersResponse {
+            queued: req.state().queue.len(),
+            workers: req.state().workers.snapshot(),
+        }).unwrap())
+}
+
+/// Compares the strings in time independent of where they differ, so that
+/// a secret can't be guessed byte by byte from response times.
+fn secret_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len() &&
+        a.bytes().zip(b.bytes()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Cancels the queued or running conversion of the book, and returns the
+/// job. Only the client that queued the job may cancel it, by passing the
+/// `cancel_token` it got from the reader status as the `token` parameter.
+pub fn cancel_conversion(req: &HttpRequest<AppState>) -> HttpResponse {
+    let bookid: i64 =
+        req.match_info().get("bookid").unwrap().parse().unwrap();
+    let job = match req.state().jobs.get(bookid) {
+        Some(ref job) if job.is_in_flight() => job.clone(),
+        _ => return HttpResponse::new(StatusCode::NOT_FOUND)
+    };
+    let authorized = match (job.cancel_token, req.query().get("token")) {
+        (Some(ref expected), Some(token)) => secret_eq(expected, token),
+        _ => false
+    };
+    if !authorized {
+        return HttpResponse::new(StatusCode::FORBIDDEN);
+    }
+    // Cancels by generation, in case the job finished and another one was
+    // queued since it was looked up.
+    let generation = job.generation;
+    if !req.state().jobs.cancel(bookid, generation) {
+        return HttpResponse::new(StatusCode::NOT_FOUND);
+    }
+    // A running job is stopped by its worker instead. Only the task of the
+    // cancelled job is removed, since another request may have queued a new
+    // one in the meantime.
+    if req.state().queue.remove(bookid, generation) {
+        if let Err(e) = req.state().store.remove_task(bookid, generation) {
+            warn!("Failed to remove the task of book {} from the store: {}", bookid, e);
+        }
+    }
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(serde_json::to_string(&req.state().jobs.get(bookid)).unwrap())
+}
+
+#[derive(Serialize)]
+struct ComicPages {
+    source_format: String,
+    /// Names of the page images in reading order
+    pages: Vec<String>,
+}
+
+fn comic_error_response(bookid: i64, e: ComicError) -> HttpResponse {
+    match e {
+        ComicError::NoSuchPageError(_) => HttpResponse::new(StatusCode::NOT_FOUND),
+        e => {
+            warn!("Failed to read comic {}: {}", bookid, e);
+            HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+pub fn get_comic_pages(req: &HttpRequest<AppState>) -> HttpResponse {
+    let bookid: i64 =
+        req.match_info().get("bookid").unwrap().parse().unwrap();
+    let conn = req.state().get_meta_data_conn();
+    let sources = find_sources(&conn, &req.state().data_path, bookid);
+    let source = match find_comic_source(&sources) {
+        Some(source) => source,
+        None => return HttpResponse::new(StatusCode::NOT_FOUND)
+    };
+    let pages = ComicCache::open(&req.state().cache, bookid, source,
+                                 &req.state().extractors)
+        .and_then(|comic| comic.pages());
+    match pages {
+        Ok(pages) => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(serde_json::to_string(&ComicPages {
+                source_format: source.format(),
+                pages: pages,
+            }).unwrap()),
+        Err(e) => comic_error_response(bookid, e)
+    }
+}
+
+/// Serves page `n` (0-origin) of a comic, downscaled if `width` is given.
+pub fn get_comic_page(req: &HttpRequest<AppState>) -> impl Responder {
+    let bookid: i64 =
+        req.match_info().get("bookid").unwrap().parse().unwrap();
+    let n: usize = match req.match_info().get("n").unwrap().parse() {
+        Ok(n) => n,
+        Err(_) => return EitherResponder::B(HttpResponse::new(StatusCode::NOT_FOUND))
+    };
+    let width: Option<u32> = match req.query().get("width") {
+        Some(s) => match s.parse() {
+            Ok(width) if width > 0 => Some(width),
+            _ => return EitherResponder::B(
+                HttpResponse::BadRequest().body("Width must be a positive integer"))
+        },
+        None => None
+    };
+    let conn = req.state().get_meta_data_conn();
+    let sources = find_sources(&conn, &req.state().data_path, bookid);
+    let source = match find_comic_source(&sources) {
+        Some(source) => source,
+        None => return EitherResponder::B(HttpResponse::new(StatusCode::NOT_FOUND))
+    };
+    let page_path = ComicCache::open(&req.state().cache, bookid, source,
+                                     &req.state().extractors)
+        .and_then(|comic| comic.page(n, width));
+    match page_path.and_then(|path| fs::NamedFile::open(path).map_err(ComicError::from)) {
+        Ok(file) => {
+            req.state().cache.touch_comic(bookid);
+            EitherResponder::A(file)
+        }
+        Err(e) => EitherResponder::B(comic_error_response(bookid, e))
+    }
+}
 
-        is_ready = "false";
+#[cfg(test)]
+mod tests {
+    use super::is_public_book_path;
+    use std::path::Path;
+
+    #[test]
+    fn public_book_paths() {
+        assert!(is_public_book_path(Path::new("OEBPS/text.xhtml")));
+        assert!(is_public_book_path(Path::new("META-INF/container.xml")));
+        assert!(!is_public_book_path(Path::new(".source.json")));
+        assert!(!is_public_book_path(Path::new(".last-access")));
+        assert!(!is_public_book_path(Path::new("OEBPS/.hidden/text.xhtml")));
+        assert!(!is_public_book_path(Path::new("../1/.source.json")));
+        assert!(!is_public_book_path(Path::new("OEBPS/../../secret")));
+        assert!(!is_public_book_path(Path::new("/etc/passwd")));
     }
-    format!(
-        r#"{{"is_ready": {}, "uri": "{}"}}"#,
-        is_ready, reader_uri)
 }
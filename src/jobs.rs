@@ -0,0 +1,398 @@
+//! Registry of conversion jobs shared between the converter thread and the
+//! HTTP handlers, so that clients can observe the progress and failures of
+//! conversions.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+/// Shared flag through which a running conversion is asked to stop.
+#[derive(Clone, Debug, Default)]
+pub struct CancelFlag(Arc<AtomicBool>);
+
+impl CancelFlag {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Latest conversion job of a book. Timestamps are in seconds since the epoch.
+#[derive(Clone, Debug, Serialize)]
+pub struct Job {
+    pub bookid: i64,
+    pub state: JobState,
+    pub error: Option<String>,
+    /// Percentage reported by the converter while running
+    pub progress: Option<u8>,
+    /// Format of the source file the successful conversion was made from
+    pub source_format: Option<String>,
+    pub queued_at: u64,
+    pub started_at: Option<u64>,
+    pub finished_at: Option<u64>,
+    /// Tells the job apart from earlier jobs of the same book, and matches
+    /// the `generation` of its task
+    #[serde(skip)]
+    pub generation: u64,
+    #[serde(skip)]
+    pub cancel: CancelFlag,
+    /// Secret that lets the client which queued the job cancel it. None if
+    /// no random bytes were available, making the job uncancellable.
+    #[serde(skip)]
+    pub cancel_token: Option<String>,
+}
+
+impl JobState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            JobState::Queued => "queued",
+            JobState::Running => "running",
+            JobState::Succeeded => "succeeded",
+            JobState::Failed => "failed",
+            JobState::Cancelled => "cancelled",
+        }
+    }
+}
+
+impl Job {
+    pub fn is_in_flight(&self) -> bool {
+        self.state == JobState::Queued || self.state == JobState::Running
+    }
+}
+
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Makes an unguessable token as 32 hex digits.
+fn new_cancel_token() -> Option<String> {
+    let mut bytes = [0u8; 16];
+    match File::open("/dev/urandom").and_then(|mut file| file.read_exact(&mut bytes)) {
+        Ok(()) => Some(bytes.iter().map(|b| format!("{:02x}", b)).collect()),
+        Err(e) => {
+            warn!("Failed to make a cancel token: {}", e);
+            None
+        }
+    }
+}
+
+struct Registry {
+    jobs: HashMap<i64, Job>,
+    next_generation: u64,
+    /// Clients listening to the job updates of each book
+    subscribers: HashMap<i64, Vec<UnboundedSender<Job>>>,
+}
+
+impl Registry {
+    /// Sends the job to its subscribers. Once the job is finished, the
+    /// subscriptions are closed since no more updates will follow.
+    fn notify(&mut self, bookid: i64) {
+        let job = match self.jobs.get(&bookid) {
+            Some(job) => job.clone(),
+            None => return
+        };
+        if job.is_in_flight() {
+            if let Some(senders) = self.subscribers.get_mut(&bookid) {
+                senders.retain(|sender| sender.unbounded_send(job.clone()).is_ok());
+            }
+        } else if let Some(senders) = self.subscribers.remove(&bookid) {
+            for sender in senders {
+                let _ = sender.unbounded_send(job.clone());
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct JobRegistry {
+    inner: Arc<Mutex<Registry>>,
+}
+
+impl JobRegistry {
+    pub fn new() -> Self {
+        JobRegistry {
+            inner: Arc::new(Mutex::new(Registry {
+                jobs: HashMap::new(),
+                next_generation: 0,
+                subscribers: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Each method leaves the registry consistent before doing anything that
+    /// may panic, so it's still usable after a worker panicked holding it.
+    fn lock(&self) -> MutexGuard<Registry> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn get(&self, bookid: i64) -> Option<Job> {
+        self.lock().jobs.get(&bookid).cloned()
+    }
+
+    /// Streams updates of the job of the book, starting with its current
+    /// state, and ending once it's finished. Returns None if the book has no
+    /// job.
+    pub fn subscribe(&self, bookid: i64) -> Option<UnboundedReceiver<Job>> {
+        let mut inner = self.lock();
+        let job = match inner.jobs.get(&bookid) {
+            Some(job) => job.clone(),
+            None => return None
+        };
+        let (sender, receiver) = unbounded();
+        let in_flight = job.is_in_flight();
+        let _ = sender.unbounded_send(job);
+        if in_flight {
+            inner.subscribers.entry(bookid).or_insert_with(Vec::new).push(sender);
+        }
+        Some(receiver)
+    }
+
+    /// Registers a new job for the book unless one is already queued or
+    /// running, replacing any finished one, and returns its generation.
+    /// Returns None if the caller should attach to the existing job instead
+    /// of enqueuing a task.
+    pub fn try_queue(&self, bookid: i64) -> Option<u64> {
+        let mut inner = self.lock();
+        if let Some(job) = inner.jobs.get(&bookid) {
+            if job.is_in_flight() {
+                return None;
+            }
+        }
+        let generation = inner.next_generation;
+        inner.next_generation += 1;
+        inner.jobs.insert(bookid, Job {
+            bookid: bookid,
+            state: JobState::Queued,
+            error: None,
+            progress: None,
+            source_format: None,
+            queued_at: now(),
+            started_at: None,
+            finished_at: None,
+            generation: generation,
+            cancel: CancelFlag::default(),
+            cancel_token: new_cancel_token(),
+        });
+        inner.notify(bookid);
+        Some(generation)
+    }
+
+    /// Marks the queued job of the book as running and returns its cancel
+    /// flag, or None if the job was cancelled while queued, or the task is
+    /// left over from an earlier job.
+    pub fn running(&self, bookid: i64, generation: u64) -> Option<CancelFlag> {
+        let mut inner = self.lock();
+        let cancel = match inner.jobs.get_mut(&bookid) {
+            Some(job) => {
+                if job.state != JobState::Queued || job.generation != generation {
+                    return None;
+                }
+                job.state = JobState::Running;
+                job.started_at = Some(now());
+                job.cancel.clone()
+            },
+            None => {
+                warn!("Status update for unknown job of book {}", bookid);
+                return None;
+            }
+        };
+        inner.notify(bookid);
+        Some(cancel)
+    }
+
+    pub fn progress(&self, bookid: i64, generation: u64, percent: u8) {
+        let mut inner = self.lock();
+        match inner.jobs.get_mut(&bookid) {
+            Some(job) => {
+                if job.state != JobState::Running || job.generation != generation
+                    || job.progress == Some(percent) {
+                    return;
+                }
+                job.progress = Some(percent);
+            },
+            None => return
+        }
+        inner.notify(bookid);
+    }
+
+    pub fn succeeded(&self, bookid: i64, generation: u64, source_format: String) {
+        self.update(bookid, generation, |job| {
+            job.state = JobState::Succeeded;
+            job.source_format = Some(source_format);
+            job.finished_at = Some(now());
+        });
+    }
+
+    pub fn failed(&self, bookid: i64, generation: u64, error: String) {
+        self.update(bookid, generation, |job| {
+            job.state = JobState::Failed;
+            job.error = Some(error);
+            job.finished_at = Some(now());
+        });
+    }
+
+    /// Requests cancellation of the given job of the book. A queued job is
+    /// cancelled right away, while a running one is stopped by its worker.
+    /// Returns false if the job is no longer in flight, or was replaced.
+    pub fn cancel(&self, bookid: i64, generation: u64) -> bool {
+        let mut inner = self.lock();
+        match inner.jobs.get_mut(&bookid) {
+            Some(job) => {
+                if !job.is_in_flight() || job.generation != generation {
+                    return false;
+                }
+                job.cancel.cancel();
+                if job.state == JobState::Queued {
+                    job.state = JobState::Cancelled;
+                    job.finished_at = Some(now());
+                }
+            },
+            None => return false
+        }
+        inner.notify(bookid);
+        true
+    }
+
+    /// Drops the job of the book, e.g. when its task couldn't be queued.
+    pub fn forget(&self, bookid: i64) {
+        let mut inner = self.lock();
+        inner.jobs.remove(&bookid);
+        // Closes the subscriptions
+        inner.subscribers.remove(&bookid);
+    }
+
+    pub fn cancelled(&self, bookid: i64, generation: u64) {
+        self.update(bookid, generation, |job| {
+            job.state = JobState::Cancelled;
+            job.finished_at = Some(now());
+        });
+    }
+
+    /// Applies a status update of a job, unless the book has moved on to
+    /// another job since, e.g. when the job was cancelled while queued and
+    /// queued again.
+    fn update<F>(&self, bookid: i64, generation: u64, f: F) where F: FnOnce(&mut Job) {
+        let mut inner = self.lock();
+        match inner.jobs.get_mut(&bookid) {
+            Some(job) => {
+                if job.generation != generation {
+                    debug!("Ignoring a status update for an earlier job of book {}",
+                           bookid);
+                    return;
+                }
+                f(job)
+            },
+            None => {
+                warn!("Status update for unknown job of book {}", bookid);
+                return;
+            }
+        }
+        inner.notify(bookid);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{JobRegistry, JobState};
+
+    #[test]
+    fn queue_once_per_book() {
+        let jobs = JobRegistry::new();
+        let first = jobs.try_queue(1).unwrap();
+        // Attaches to the job in flight
+        assert_eq!(jobs.try_queue(1), None);
+        let other = jobs.try_queue(2).unwrap();
+        assert!(other != first);
+
+        assert!(jobs.running(1, first).is_some());
+        assert_eq!(jobs.try_queue(1), None);
+        jobs.failed(1, first, "broken".to_string());
+        // Finished jobs are replaced by a new generation
+        let second = jobs.try_queue(1).unwrap();
+        assert!(second != first);
+        let job = jobs.get(1).unwrap();
+        assert_eq!(job.state, JobState::Queued);
+        assert_eq!(job.generation, second);
+        assert_eq!(job.error, None);
+    }
+
+    #[test]
+    fn running_checks_generation() {
+        let jobs = JobRegistry::new();
+        let first = jobs.try_queue(1).unwrap();
+        jobs.forget(1);
+        let second = jobs.try_queue(1).unwrap();
+        // A task left over from the forgotten job
+        assert!(jobs.running(1, first).is_none());
+        assert_eq!(jobs.get(1).unwrap().state, JobState::Queued);
+        assert!(jobs.running(1, second).is_some());
+        // Already running
+        assert!(jobs.running(1, second).is_none());
+        assert!(jobs.running(2, 0).is_none());
+    }
+
+    #[test]
+    fn cancel_by_generation() {
+        let jobs = JobRegistry::new();
+        let first = jobs.try_queue(1).unwrap();
+        assert!(jobs.get(1).unwrap().cancel_token.is_some());
+        assert!(!jobs.cancel(1, first + 1));
+        assert!(jobs.cancel(1, first));
+        // Cancelled right away while queued
+        assert_eq!(jobs.get(1).unwrap().state, JobState::Cancelled);
+        assert!(!jobs.cancel(1, first));
+
+        let second = jobs.try_queue(1).unwrap();
+        let cancel = jobs.running(1, second).unwrap();
+        assert!(jobs.cancel(1, second));
+        // Running jobs are stopped by their worker
+        assert!(cancel.is_cancelled());
+        assert_eq!(jobs.get(1).unwrap().state, JobState::Running);
+        jobs.cancelled(1, second);
+        assert_eq!(jobs.get(1).unwrap().state, JobState::Cancelled);
+        assert!(jobs.get(2).is_none());
+        assert!(!jobs.cancel(2, 0));
+    }
+
+    #[test]
+    fn stale_updates_ignored() {
+        let jobs = JobRegistry::new();
+        let first = jobs.try_queue(1).unwrap();
+        jobs.cancel(1, first);
+        let second = jobs.try_queue(1).unwrap();
+        assert!(jobs.running(1, second).is_some());
+
+        jobs.progress(1, first, 50);
+        jobs.succeeded(1, first, "EPUB".to_string());
+        jobs.failed(1, first, "late".to_string());
+        let job = jobs.get(1).unwrap();
+        assert_eq!(job.state, JobState::Running);
+        assert_eq!(job.progress, None);
+        assert_eq!(job.error, None);
+
+        jobs.progress(1, second, 50);
+        assert_eq!(jobs.get(1).unwrap().progress, Some(50));
+        jobs.succeeded(1, second, "MOBI".to_string());
+        let job = jobs.get(1).unwrap();
+        assert_eq!(job.state, JobState::Succeeded);
+        assert_eq!(job.source_format, Some("MOBI".to_string()));
+    }
+}
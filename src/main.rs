@@ -21,32 +21,62 @@ extern crate structopt;
 extern crate rusoto_core;
 extern crate rusoto_s3;
 extern crate futures;
+extern crate bytes;
 extern crate hyper;
+extern crate image;
+extern crate xml;
+extern crate zip;
+extern crate pulldown_cmark;
+extern crate libc;
+extern crate ammonia;
 
 use std::path::PathBuf;
-use std::sync::{mpsc, Arc};
-use std::sync::mpsc::{SyncSender, Receiver};
-use std::thread;
+use std::sync::Arc;
+use std::time::Duration;
 
 use actix_web::{server, App, fs, middleware};
+use actix_web::http::Method;
 use structopt::StructOpt;
 
 mod db;
 mod worker;
 mod httphandler;
 mod cache;
+mod jobs;
+mod search;
+mod opds;
+mod webpub;
+mod converter;
+mod store;
+mod comic;
 
-use worker::worker_loop;
-use httphandler::{get_main_page, get_reader_page, get_book_list,
+use worker::{convert_all, find_sources, resume_tasks, spawn_workers, ConversionTask,
+             Priority, TaskQueue, WorkerContext, WorkerHealth};
+use jobs::JobRegistry;
+use httphandler::{get_main_page, get_reader_page, get_pdf_reader_page,
+                  get_comic_reader_page, get_comic_pages, get_comic_page,
+                  get_cached_book_file,
+                  get_book_list, get_book_metadata, get_book_page, get_book_cover,
                   get_book_data, get_reader_status,
-                  AppConfig};
+                  cancel_conversion, get_worker_status, get_job_events,
+                  get_conversion_log, has_pdf_viewer, native_reader, AppConfig};
+use db::{BookList, Category, DBConnector};
+use cache::{check_cache_freshness, parse_size, CacheManager};
+use converter::{parse_backend_assignment, Backend, Converters, Limits};
+use store::Store;
+use comic::Extractors;
 
+/// Maximum number of conversion tasks waiting for a worker
+const QUEUE_CAPACITY: usize = 100;
 
 #[derive(StructOpt, Debug, Clone)]
 #[structopt(name = "basic")]
 struct Opt {
     #[structopt(short = "d", long = "db")]
     meta_data_db: String,
+    /// Dir of the static files. PDFs are read without converting them if
+    /// pdf.js is installed there as js/pdf.min.js and js/pdf.worker.min.js
+    /// (from the build/ dir of a pdfjs-dist 2.x release)
     #[structopt(short = "s", long = "static-pages", parse(from_os_str))]
     static_path: PathBuf,
     #[structopt(short = "c", long = "cache-dir", parse(from_os_str))]
@@ -55,6 +85,33 @@ struct Opt {
     data_path: Option<PathBuf>,
     #[structopt(short = "C", long = "converter", default_value = "ebook-convert")]
     converter_bin: String,
+    #[structopt(long = "pandoc", default_value = "pandoc")]
+    pandoc_bin: String,
+    /// Extractor of CBR comics
+    #[structopt(long = "unrar", default_value = "unrar")]
+    unrar_bin: String,
+    /// Extractor of CB7 comics
+    #[structopt(long = "7z", default_value = "7z")]
+    sevenzip_bin: String,
+    /// Converter backend for a source format, e.g. "TXT=native" or
+    /// "MD=pandoc" (backends: calibre, pandoc, native; default: calibre)
+    #[structopt(long = "converter-for", parse(try_from_str = "parse_backend_assignment"))]
+    converter_for: Vec<(String, Backend)>,
+    /// Wall-clock limit of each conversion job in seconds (0 for no limit)
+    #[structopt(long = "conversion-timeout", default_value = "900")]
+    conversion_timeout: u64,
+    /// CPU time limit of converter and comic extractor processes in seconds
+    #[structopt(long = "converter-cpu-limit")]
+    converter_cpu_limit: Option<u64>,
+    /// Address space limit of converter and comic extractor processes
+    /// (e.g. "2G")
+    #[structopt(long = "converter-memory-limit", parse(try_from_str = "parse_size"))]
+    converter_memory_limit: Option<u64>,
+    /// Limit on the size of files written by converter processes (e.g. "1G")
+    #[structopt(long = "converter-file-size-limit", parse(try_from_str = "parse_size"))]
+    converter_file_size_limit: Option<u64>,
+    #[structopt(short = "w", long = "workers", default_value = "1")]
+    workers: usize,
     #[structopt(short = "v", long = "verbose", parse(from_occurrences))]
     verbosity: usize,
     #[structopt(short = "p", long = "app-uri-prefix", default_value = "")]
@@ -62,86 +119,363 @@ struct Opt {
     #[structopt(short = "b", long = "bind-to", default_value = "0.0.0.0:8000")]
     bind_to: String,
     #[structopt(long = "s3-region", default_value = "us-east-1")]
-    s3_region: String
+    s3_region: String,
+    /// Maximum total size of extracted books in the cache dir (e.g. "2G")
+    #[structopt(long = "cache-limit", parse(try_from_str = "parse_size"))]
+    cache_limit: Option<u64>,
+    #[structopt(subcommand)]
+    cmd: Option<Command>
 }
 
-impl Opt {
-    pub fn make_app_config(self,
-                           conv_task_tx: SyncSender<(PathBuf, PathBuf)>)
-                           -> AppConfig {
+#[derive(StructOpt, Debug, Clone)]
+enum Command {
+    /// Maintains the cache dir
+    #[structopt(name = "cache")]
+    Cache {
+        #[structopt(subcommand)]
+        cmd: CacheCommand
+    },
+    /// Converts every book missing from the cache, e.g. to warm it overnight
+    #[structopt(name = "convert-all")]
+    ConvertAll {
+        /// Number of conversions to run in parallel
+        #[structopt(short = "j", long = "jobs", default_value = "1")]
+        jobs: usize,
+        /// Only convert books with one of these tags
+        #[structopt(long = "tag")]
+        tags: Vec<String>,
+        /// Only convert books available in one of these formats
+        #[structopt(long = "format")]
+        formats: Vec<String>,
+    }
+}
+
+#[derive(StructOpt, Debug, Clone)]
+enum CacheCommand {
+    /// Evicts least recently used books until the cache fits in --cache-limit
+    #[structopt(name = "gc")]
+    Gc {
+        /// Only report what would be removed
+        #[structopt(short = "n", long = "dry-run")]
+        dry_run: bool
+    },
+    /// Forgets conversion failures so that the books are converted again
+    #[structopt(name = "clear-failures")]
+    ClearFailures {
+        /// Books to retry; all books if omitted
+        bookids: Vec<i64>
+    }
+}
 
+impl Opt {
+    /// Returns the connector to the metadata DB, and the root of the book
+    /// files.
+    fn make_db_connector(&self) -> (Box<DBConnector>, PathBuf) {
         // As an intermediate solution, only metadata can be read from S3 directly.
         // In case the meta_data_db is S3 URI, there's no way to resolve data path.
         // Therefore, the startup process will exit with an error code, then.
 
         if self.meta_data_db.starts_with("s3://") {
-            let data_path = self.data_path.expect(
+            let data_path = self.data_path.clone().expect(
                 "Need to specify data path explicitly when metadata is from S3");
-            AppConfig {
-                db_connector: box db::S3DBConnector::new(
-                    &self.meta_data_db,
-                    &self.s3_region),
-                static_path: self.static_path,
-                cache_path: self.cache_path,
-                data_path: data_path,
-                app_prefix: self.app_prefix,
-                conv_task_tx: conv_task_tx,
-            }
+            (box db::S3DBConnector::new(&self.meta_data_db, &self.s3_region),
+             data_path)
         } else {
             let default_data_path = {
                 let mut p = PathBuf::from(self.meta_data_db.clone());
                 p.pop();
                 p
             };
+            (box db::LocalDBConnector::new(&self.meta_data_db),
+             self.data_path.clone().unwrap_or(default_data_path))
+        }
+    }
 
-            AppConfig {
-                db_connector: box db::LocalDBConnector::new(&self.meta_data_db),
-                static_path: self.static_path,
-                cache_path: self.cache_path,
-                data_path: self.data_path.unwrap_or(default_data_path),
-                app_prefix: self.app_prefix,
-                conv_task_tx: conv_task_tx
-            }
+    /// Resource limits of converter and extractor processes
+    fn converter_limits(&self) -> Limits {
+        Limits {
+            cpu_time: self.converter_cpu_limit,
+            memory: self.converter_memory_limit,
+            file_size: self.converter_file_size_limit,
+        }
+    }
+
+    fn make_worker_context(&self, queue: TaskQueue, jobs: JobRegistry,
+                           cache: CacheManager, store: Store) -> WorkerContext {
+        let limits = self.converter_limits();
+        let timeout = if self.conversion_timeout > 0 {
+            Some(Duration::from_secs(self.conversion_timeout))
+        } else {
+            None
+        };
+        WorkerContext {
+            converters: Arc::new(Converters::new(&self.converter_bin, &self.pandoc_bin,
+                                                 &self.converter_for, limits)),
+            queue: queue,
+            jobs: jobs,
+            cache: cache,
+            store: store,
+            timeout: timeout,
+            collect_garbage: true,
+        }
+    }
+
+    pub fn make_app_config(self,
+                           queue: TaskQueue,
+                           jobs: JobRegistry,
+                           cache: CacheManager,
+                           store: Store,
+                           workers: WorkerHealth)
+                           -> AppConfig {
+        let (db_connector, data_path) = self.make_db_connector();
+        let limits = self.converter_limits();
+        let pdf_viewer = has_pdf_viewer(&self.static_path);
+        if !pdf_viewer {
+            info!("pdf.js is not installed in {:?}; PDFs will be converted. \
+                   Copy pdf.min.js and pdf.worker.min.js of pdfjs-dist to its js/ \
+                   dir to read them as they are", self.static_path);
+        }
+        AppConfig {
+            db_connector: db_connector,
+            static_path: self.static_path,
+            cache: cache,
+            cache_path: self.cache_path,
+            data_path: data_path,
+            app_prefix: self.app_prefix,
+            queue: queue,
+            jobs: jobs,
+            store: store,
+            workers: workers,
+            extractors: Extractors {
+                unrar: self.unrar_bin,
+                sevenzip: self.sevenzip_bin,
+                limits: limits,
+            },
+            pdf_viewer: pdf_viewer,
         }
     }
 }
 
+fn run_cache_gc(opt: &Opt, dry_run: bool) {
+    if opt.cache_limit.is_none() {
+        error!("--cache-limit is required for cache gc");
+        ::std::process::exit(1);
+    }
+    let cache = CacheManager::new(opt.cache_path.clone(), opt.cache_limit);
+    match cache.collect_garbage(dry_run, None) {
+        Ok(report) => {
+            info!("{} {} book(s), {} bytes; {} bytes remain",
+                  if dry_run { "Would remove" } else { "Removed" },
+                  report.removed.len(), report.freed, report.remaining);
+        },
+        Err(e) => {
+            error!("Cache GC failed: {}", e);
+            ::std::process::exit(1);
+        }
+    }
+}
+
+fn open_store(opt: &Opt) -> Store {
+    match Store::open(&opt.cache_path) {
+        Ok(store) => store,
+        Err(e) => {
+            error!("Failed to open the store in {:?}: {}", opt.cache_path, e);
+            ::std::process::exit(1);
+        }
+    }
+}
+
+fn run_clear_failures(opt: &Opt, bookids: &[i64]) {
+    let store = open_store(opt);
+    let result = if bookids.is_empty() {
+        store.clear_all_failures()
+    } else {
+        bookids.iter().fold(Ok(0), |acc, bookid| {
+            acc.and_then(|n| store.clear_failure(*bookid).map(|cleared| {
+                if cleared { n + 1 } else { n }
+            }))
+        })
+    };
+    match result {
+        Ok(n) => info!("Cleared {} conversion failure(s)", n),
+        Err(e) => {
+            error!("Failed to clear conversion failures: {}", e);
+            ::std::process::exit(1);
+        }
+    }
+}
+
+fn run_convert_all(opt: &Opt, parallelism: usize, tags: &[String], formats: &[String]) {
+    let (db_connector, data_path) = opt.make_db_connector();
+    let conn = db_connector.get_connection();
+    let store = open_store(opt);
+    let tags: Vec<String> = tags.iter().map(|t| t.to_lowercase()).collect();
+    let formats: Vec<String> = formats.iter().map(|f| f.to_uppercase()).collect();
+
+    let mut bookids = Vec::new();
+    BookList::new(&conn).for_each(|book| {
+        let tag_matches = tags.is_empty() ||
+            book.tags.iter().any(|t| tags.contains(&t.to_lowercase()));
+        let format_matches = formats.is_empty() ||
+            book.available_data.iter().any(|f| formats.contains(f));
+        if tag_matches && format_matches {
+            bookids.push(book.id);
+        }
+    });
+
+    let pdf_viewer = has_pdf_viewer(&opt.static_path);
+    let jobs = JobRegistry::new();
+    let mut tasks = Vec::new();
+    for bookid in bookids {
+        let mut reader_path = opt.cache_path.clone();
+        reader_path.push(format!("{}", bookid));
+        let sources = find_sources(&conn, &data_path, bookid);
+        if sources.is_empty() || check_cache_freshness(&reader_path, &sources).is_some() {
+            continue;
+        }
+        if let Some((route, _)) = native_reader(&sources, pdf_viewer) {
+            debug!("Skipping book {} read by the {} reader", bookid, route);
+            continue;
+        }
+        match store.get_failure(bookid, &sources) {
+            Ok(Some(_)) => {
+                debug!("Skipping book {} known to fail conversion", bookid);
+                continue;
+            },
+            Ok(None) => {},
+            Err(e) => warn!("Failed to look up the conversion failure of book {}: {}",
+                            bookid, e)
+        }
+        let generation = match jobs.try_queue(bookid) {
+            Some(generation) => generation,
+            None => continue
+        };
+        tasks.push(ConversionTask {
+            bookid: bookid,
+            dest: reader_path,
+            sources: sources,
+            options: BookList::new(&conn).conversion_options(bookid),
+            priority: Priority::Background,
+            generation: generation,
+        });
+    }
+
+    println!("Converting {} book(s) with {} job(s)...", tasks.len(), parallelism);
+    let queue = TaskQueue::new(tasks.len());
+    for task in tasks {
+        // Can't fail; the queue has room for all of them.
+        let _ = queue.try_push(task);
+    }
+    let cache = CacheManager::new(opt.cache_path.clone(), opt.cache_limit);
+    let mut ctx = opt.make_worker_context(queue, jobs, cache, store);
+    ctx.collect_garbage = false;
+    let report = convert_all(ctx, parallelism);
+    println!("Converted {} book(s); {} failed, {} skipped",
+             report.succeeded, report.failed, report.skipped);
+}
+
 fn main() {
     let opt = Opt::from_args();
 
     stderrlog::new().verbosity(opt.verbosity).init().unwrap();
 
-    info!("Starting e-book converter thread...");
-    let (tx, rx): (SyncSender<(PathBuf, PathBuf)>, Receiver<(PathBuf, PathBuf)>) =
-        mpsc::sync_channel(100);
+    match opt.cmd {
+        Some(Command::Cache { cmd: CacheCommand::Gc { dry_run } }) => {
+            run_cache_gc(&opt, dry_run);
+            return;
+        },
+        Some(Command::Cache { cmd: CacheCommand::ClearFailures { ref bookids } }) => {
+            run_clear_failures(&opt, bookids);
+            return;
+        },
+        Some(Command::ConvertAll { jobs, ref tags, ref formats }) => {
+            run_convert_all(&opt, jobs, tags, formats);
+            return;
+        },
+        None => {}
+    }
 
-    let converter_bin = opt.converter_bin.clone();
-    thread::spawn(move || {
-        worker_loop(&converter_bin, rx);
-    });
+    info!("Starting {} e-book converter thread(s)...", opt.workers);
+    let queue = TaskQueue::new(QUEUE_CAPACITY);
+    let jobs = JobRegistry::new();
+    let cache = CacheManager::new(opt.cache_path.clone(), opt.cache_limit);
+    let store = open_store(&opt);
 
-    let conf = Arc::new(opt.clone().make_app_config(tx));
+    let ctx = opt.make_worker_context(queue.clone(), jobs.clone(), cache.clone(),
+                                      store.clone());
+    {
+        let (db_connector, data_path) = opt.make_db_connector();
+        resume_tasks(&ctx, &db_connector.get_connection(), &data_path);
+    }
+    let workers = spawn_workers(opt.workers.max(1), ctx);
+
+    let conf = Arc::new(opt.clone().make_app_config(queue, jobs, cache, store, workers));
 
     server::new(move || {
         App::with_state(conf.clone())
             .prefix(conf.app_prefix.clone())
             .middleware(middleware::Logger::default())
             .resource("/api/booklist.js", |r| r.f(get_book_list))
+            .resource("/api/workers.js", |r| r.f(get_worker_status))
+            .resource("/api/{bookid}/metadata.js",
+                      |r| r.f(get_book_metadata))
             .resource("/api/{bookid}/reader_status.js",
                       |r| r.f(get_reader_status))
+            .resource("/api/{bookid}/conversion_log.txt",
+                      |r| r.f(get_conversion_log))
+            .resource("/api/{bookid}/events",
+                      |r| r.f(get_job_events))
+            .resource("/api/{bookid}/cancel.js",
+                      |r| r.method(Method::POST).f(cancel_conversion))
+            .resource("/api/{bookid}/comic_pages.js",
+                      |r| r.f(get_comic_pages))
+            .resource("/api/{bookid}/manifest.json",
+                      |r| r.f(webpub::get_manifest))
+            .resource("/opds/v2/catalog.json", |r| r.f(webpub::get_catalog))
+            .resource("/opds", |r| r.f(opds::get_root))
+            .resource("/opds/opensearch.xml",
+                      |r| r.f(opds::get_opensearch_description))
+            .resource("/opds/search", |r| r.f(opds::get_search))
+            .resource("/opds/new", |r| r.f(opds::get_newest))
+            .resource("/opds/books", |r| r.f(opds::get_all_books))
+            .resource("/opds/authors",
+                      |r| r.f(|req| opds::get_category_list(req, Category::Author)))
+            .resource("/opds/authors/{id}",
+                      |r| r.f(|req| opds::get_category_books(req, Category::Author)))
+            .resource("/opds/series",
+                      |r| r.f(|req| opds::get_category_list(req, Category::Series)))
+            .resource("/opds/series/{id}",
+                      |r| r.f(|req| opds::get_category_books(req, Category::Series)))
+            .resource("/opds/tags",
+                      |r| r.f(|req| opds::get_category_list(req, Category::Tag)))
+            .resource("/opds/tags/{id}",
+                      |r| r.f(|req| opds::get_category_books(req, Category::Tag)))
             .resource("", |r| r.f(get_main_page))
             .resource("/", |r| r.f(get_main_page))
             .resource(
                 "/data/{bookid}/{datatype}",
                 |r| r.f(get_book_data))
+            .resource(
+                "/details/{bookid}",
+                |r| r.f(get_book_page))
+            .resource(
+                "/cover/{bookid}",
+                |r| r.f(get_book_cover))
             .resource(
                 "/reader/{bookid}",
                 |r| r.f(get_reader_page))
-            .handler(
-                "/book",
-                fs::StaticFiles::new(conf.cache_path.to_str().unwrap())
-                    .unwrap()
-                    .show_files_listing())
+            .resource(
+                "/pdfreader/{bookid}",
+                |r| r.f(get_pdf_reader_page))
+            .resource(
+                "/comic/{bookid}",
+                |r| r.f(get_comic_reader_page))
+            .resource(
+                "/comic/{bookid}/page/{n}",
+                |r| r.f(get_comic_page))
+            .resource(
+                "/book/{bookid}/{path:.*}",
+                |r| r.f(get_cached_book_file))
             .handler(
                 "/",
                 fs::StaticFiles::new(conf.static_path.to_str().unwrap())
@@ -0,0 +1,384 @@
+//! OPDS 1.2 catalog feeds for e-book reader applications.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::{HttpRequest, HttpResponse};
+use actix_web::http::StatusCode;
+use askama::Template;
+
+use db::{Book, BookList, BookQuery, Category, SortKey};
+use httphandler::{AppState, thumbnail_uri};
+use search;
+
+/// Number of entries in a page of a feed
+const PAGE_SIZE: i64 = 50;
+
+const NAVIGATION_TYPE: &str =
+    "application/atom+xml;profile=opds-catalog;kind=navigation";
+const ACQUISITION_TYPE: &str =
+    "application/atom+xml;profile=opds-catalog;kind=acquisition";
+const OPENSEARCH_TYPE: &str = "application/opensearchdescription+xml";
+
+/// Size of thumbnails advertised in acquisition feeds
+const FEED_THUMBNAIL_SIZE: u32 = 128;
+
+struct Link {
+    rel: String,
+    href: String,
+    mime: String,
+}
+
+impl Link {
+    fn new(rel: &str, href: String, mime: &str) -> Self {
+        Link {
+            rel: rel.to_string(),
+            href: href,
+            mime: mime.to_string(),
+        }
+    }
+}
+
+struct NavigationEntry {
+    id: String,
+    title: String,
+    content: String,
+    link: Link,
+}
+
+#[derive(Template)]
+#[template(path = "opds/navigation.xml", escape = "html")]
+struct NavigationFeed<'a> {
+    app_prefix: &'a str,
+    id: String,
+    title: String,
+    updated: String,
+    links: Vec<Link>,
+    entries: Vec<NavigationEntry>,
+}
+
+struct AcquisitionEntry {
+    id: String,
+    title: String,
+    authors: Vec<String>,
+    updated: String,
+    issued: String,
+    languages: Vec<String>,
+    publisher: String,
+    categories: Vec<String>,
+    summary: String,
+    links: Vec<Link>,
+}
+
+#[derive(Template)]
+#[template(path = "opds/acquisition.xml", escape = "html")]
+struct AcquisitionFeed<'a> {
+    app_prefix: &'a str,
+    id: String,
+    title: String,
+    updated: String,
+    links: Vec<Link>,
+    entries: Vec<AcquisitionEntry>,
+}
+
+#[derive(Template)]
+#[template(path = "opds/opensearch.xml", escape = "html")]
+struct OpenSearchDescription<'a> {
+    app_prefix: &'a str,
+}
+
+/// Formats a time as an RFC 3339 timestamp in UTC.
+pub fn format_timestamp(t: SystemTime) -> String {
+    let secs = t.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let time_of_day = secs % 86400;
+
+    // Converts days since the epoch into a civil date
+    // (http://howardhinnant.github.io/date_algorithms.html#civil_from_days)
+    let z = (secs / 86400) as i64 + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            year, month, day,
+            time_of_day / 3600, time_of_day % 3600 / 60, time_of_day % 60)
+}
+
+/// Converts Calibre's "YYYY-MM-DD HH:MM:SS+00:00" into RFC 3339.
+fn calibre_timestamp(ts: &str) -> String {
+    ts.replacen(' ', "T", 1)
+}
+
+/// MIME type of a Calibre format name
+pub fn format_mime_type(format: &str) -> &'static str {
+    match format {
+        "EPUB" => "application/epub+zip",
+        "PDF" => "application/pdf",
+        "MOBI" | "PRC" => "application/x-mobipocket-ebook",
+        "AZW" | "AZW3" | "AZW4" => "application/vnd.amazon.ebook",
+        "FB2" => "application/x-fictionbook+xml",
+        "TXT" => "text/plain",
+        "HTML" | "HTM" => "text/html",
+        "HTMLZ" => "application/zip",
+        "RTF" => "application/rtf",
+        "DOCX" => "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+        "CBZ" => "application/vnd.comicbook+zip",
+        "CBR" => "application/vnd.comicbook-rar",
+        "CB7" => "application/x-cb7",
+        "DJVU" => "image/vnd.djvu",
+        _ => "application/octet-stream"
+    }
+}
+
+fn percent_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' =>
+                encoded.push(b as char),
+            _ => encoded.push_str(&format!("%{:02X}", b))
+        }
+    }
+    encoded
+}
+
+fn request_offset(req: &HttpRequest<AppState>) -> i64 {
+    req.query().get("offset").and_then(|s| s.parse().ok()).unwrap_or(0)
+}
+
+/// Appends `offset` to `base`, which may already have a query string.
+fn page_href(base: &str, offset: i64) -> String {
+    let sep = if base.contains('?') { '&' } else { '?' };
+    format!("{}{}offset={}", base, sep, offset)
+}
+
+/// Links to the adjacent pages of a paginated feed.
+fn pagination_links(base: &str, offset: i64, total: i64, mime: &str) -> Vec<Link> {
+    let mut links = vec![Link::new("self", page_href(base, offset), mime)];
+    if offset > 0 {
+        links.push(Link::new("first", page_href(base, 0), mime));
+        links.push(Link::new("previous",
+                             page_href(base, (offset - PAGE_SIZE).max(0)), mime));
+    }
+    if offset + PAGE_SIZE < total {
+        links.push(Link::new("next", page_href(base, offset + PAGE_SIZE), mime));
+    }
+    links
+}
+
+fn acquisition_entry(app_prefix: &str, book: &Book) -> AcquisitionEntry {
+    let mut links: Vec<Link> = book.available_data.iter().map(|format| {
+        Link::new("http://opds-spec.org/acquisition",
+                  format!("{}/data/{}/{}", app_prefix, book.id, format),
+                  format_mime_type(format))
+    }).collect();
+    if let Some(thumbnail) = thumbnail_uri(app_prefix, book, FEED_THUMBNAIL_SIZE) {
+        links.push(Link::new("http://opds-spec.org/image",
+                             format!("{}/cover/{}", app_prefix, book.id),
+                             "image/jpeg"));
+        links.push(Link::new("http://opds-spec.org/image/thumbnail",
+                             thumbnail, "image/jpeg"));
+    }
+    links.push(Link::new("alternate",
+                         format!("{}/details/{}", app_prefix, book.id),
+                         "text/html"));
+
+    AcquisitionEntry {
+        id: format!("urn:uuid:{}", book.uuid),
+        title: book.title.clone(),
+        authors: book.authors.clone(),
+        updated: calibre_timestamp(&book.last_modified),
+        issued: book.pubdate.as_ref()
+            .map(|d| d.chars().take(10).collect()).unwrap_or_default(),
+        languages: book.languages.clone(),
+        publisher: book.publisher.clone().unwrap_or_default(),
+        categories: book.tags.clone(),
+        summary: book.comments.clone().unwrap_or_default(),
+        links: links,
+    }
+}
+
+fn acquisition_feed(req: &HttpRequest<AppState>, id: String, title: String,
+                    base_href: String, query: BookQuery) -> HttpResponse {
+    let app_prefix = &req.state().app_prefix;
+    let conn = req.state().get_meta_data_conn();
+    let booklist = BookList::new(&conn);
+
+    let mut query = query;
+    query.offset = request_offset(req);
+    query.limit = Some(PAGE_SIZE);
+
+    let mut entries = Vec::new();
+    booklist.for_each_in(&query, |book| {
+        entries.push(acquisition_entry(app_prefix, book));
+    });
+
+    HttpResponse::Ok()
+        .content_type(ACQUISITION_TYPE)
+        .body(AcquisitionFeed {
+            app_prefix: app_prefix,
+            id: id,
+            title: title,
+            updated: format_timestamp(SystemTime::now()),
+            links: pagination_links(&base_href, query.offset,
+                                    booklist.count(&query), ACQUISITION_TYPE),
+            entries: entries,
+        }.render().unwrap())
+}
+
+fn category_path(category: Category) -> &'static str {
+    match category {
+        Category::Author => "authors",
+        Category::Series => "series",
+        Category::Tag => "tags",
+    }
+}
+
+pub fn get_root(req: &HttpRequest<AppState>) -> HttpResponse {
+    let app_prefix = &req.state().app_prefix;
+    let nav = |id: &str, title: &str, content: &str, rel: &str, path: &str, mime: &str| {
+        NavigationEntry {
+            id: format!("urn:weblibri:{}", id),
+            title: title.to_string(),
+            content: content.to_string(),
+            link: Link::new(rel, format!("{}/opds/{}", app_prefix, path), mime),
+        }
+    };
+    let entries = vec![
+        nav("authors", "Authors", "Books by author",
+            "subsection", "authors", NAVIGATION_TYPE),
+        nav("series", "Series", "Books by series",
+            "subsection", "series", NAVIGATION_TYPE),
+        nav("tags", "Tags", "Books by tag",
+            "subsection", "tags", NAVIGATION_TYPE),
+        nav("new", "Newest", "Recently added books",
+            "http://opds-spec.org/sort/new", "new", ACQUISITION_TYPE),
+        nav("books", "All books", "All books by title",
+            "subsection", "books", ACQUISITION_TYPE),
+    ];
+
+    HttpResponse::Ok()
+        .content_type(NAVIGATION_TYPE)
+        .body(NavigationFeed {
+            app_prefix: app_prefix,
+            id: String::from("urn:weblibri:root"),
+            title: String::from("Weblibri"),
+            updated: format_timestamp(SystemTime::now()),
+            links: vec![Link::new("self", format!("{}/opds", app_prefix),
+                                  NAVIGATION_TYPE)],
+            entries: entries,
+        }.render().unwrap())
+}
+
+pub fn get_category_list(req: &HttpRequest<AppState>, category: Category)
+                         -> HttpResponse {
+    let app_prefix = &req.state().app_prefix;
+    let conn = req.state().get_meta_data_conn();
+    let booklist = BookList::new(&conn);
+    let offset = request_offset(req);
+    let path = category_path(category);
+
+    let entries = booklist.categories(category, offset, PAGE_SIZE).into_iter()
+        .map(|item| NavigationEntry {
+            id: format!("urn:weblibri:{}:{}", path, item.id),
+            title: item.name,
+            content: format!("{} books", item.count),
+            link: Link::new("subsection",
+                            format!("{}/opds/{}/{}", app_prefix, path, item.id),
+                            ACQUISITION_TYPE),
+        }).collect();
+    let base_href = format!("{}/opds/{}", app_prefix, path);
+
+    HttpResponse::Ok()
+        .content_type(NAVIGATION_TYPE)
+        .body(NavigationFeed {
+            app_prefix: app_prefix,
+            id: format!("urn:weblibri:{}", path),
+            title: match category {
+                Category::Author => String::from("Authors"),
+                Category::Series => String::from("Series"),
+                Category::Tag => String::from("Tags"),
+            },
+            updated: format_timestamp(SystemTime::now()),
+            links: pagination_links(&base_href, offset,
+                                    booklist.count_categories(category),
+                                    NAVIGATION_TYPE),
+            entries: entries,
+        }.render().unwrap())
+}
+
+pub fn get_category_books(req: &HttpRequest<AppState>, category: Category)
+                          -> HttpResponse {
+    let id: i64 = match req.match_info().get("id").and_then(|s| s.parse().ok()) {
+        Some(id) => id,
+        None => return HttpResponse::new(StatusCode::NOT_FOUND)
+    };
+    let name = {
+        let conn = req.state().get_meta_data_conn();
+        let booklist = BookList::new(&conn);
+        match booklist.category_name(category, id) {
+            Some(name) => name,
+            None => return HttpResponse::new(StatusCode::NOT_FOUND)
+        }
+    };
+    let path = category_path(category);
+
+    let mut query = BookQuery::default();
+    query.category = Some((category, id));
+    if category == Category::Series {
+        query.sort = SortKey::Series;
+    }
+    acquisition_feed(req,
+                     format!("urn:weblibri:{}:{}", path, id),
+                     name,
+                     format!("{}/opds/{}/{}", req.state().app_prefix, path, id),
+                     query)
+}
+
+pub fn get_newest(req: &HttpRequest<AppState>) -> HttpResponse {
+    let mut query = BookQuery::default();
+    query.sort = SortKey::Timestamp;
+    query.descending = true;
+    acquisition_feed(req,
+                     String::from("urn:weblibri:new"),
+                     String::from("Newest"),
+                     format!("{}/opds/new", req.state().app_prefix),
+                     query)
+}
+
+pub fn get_all_books(req: &HttpRequest<AppState>) -> HttpResponse {
+    acquisition_feed(req,
+                     String::from("urn:weblibri:books"),
+                     String::from("All books"),
+                     format!("{}/opds/books", req.state().app_prefix),
+                     BookQuery::default())
+}
+
+pub fn get_search(req: &HttpRequest<AppState>) -> HttpResponse {
+    let terms = req.query().get("q").cloned().unwrap_or_default();
+    let mut query = BookQuery::default();
+    if !terms.trim().is_empty() {
+        query.filter = match search::parse(&terms) {
+            Ok(expr) => Some(expr),
+            Err(e) => return HttpResponse::BadRequest().body(e.to_string())
+        };
+    }
+    acquisition_feed(req,
+                     format!("urn:weblibri:search:{}", percent_encode(&terms)),
+                     format!("Search results for \"{}\"", terms),
+                     format!("{}/opds/search?q={}",
+                             req.state().app_prefix, percent_encode(&terms)),
+                     query)
+}
+
+pub fn get_opensearch_description(req: &HttpRequest<AppState>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(OPENSEARCH_TYPE)
+        .body(OpenSearchDescription {
+            app_prefix: &req.state().app_prefix,
+        }.render().unwrap())
+}
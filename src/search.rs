@@ -0,0 +1,602 @@
+//! Parser for the Calibre search query language
+//! (e.g. `author:tolkien and tag:"fantasy" and not series:hobbit`) and
+//! its compilation into SQL conditions against the metadata DB.
+
+use std::{fmt, error};
+use std::iter::Peekable;
+use std::str::Chars;
+
+use rusqlite::types::Value;
+
+#[derive(Debug)]
+pub struct ParseError {
+    message: String
+}
+
+impl ParseError {
+    fn new<S: Into<String>>(message: S) -> Self {
+        ParseError { message: message.into() }
+    }
+}
+
+impl error::Error for ParseError {
+    fn description(&self) -> &str {
+        "search query parse error"
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid search query: {}", self.message)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Field {
+    Title,
+    Authors,
+    Tags,
+    Series,
+    Publisher,
+    Languages,
+    Formats,
+    Comments,
+    Identifiers,
+    Rating,
+    Pubdate,
+    Timestamp,
+    SeriesIndex,
+}
+
+impl Field {
+    fn from_name(name: &str) -> Option<Field> {
+        match name.to_lowercase().as_str() {
+            "title" => Some(Field::Title),
+            "author" | "authors" => Some(Field::Authors),
+            "tag" | "tags" => Some(Field::Tags),
+            "series" => Some(Field::Series),
+            "publisher" => Some(Field::Publisher),
+            "language" | "languages" => Some(Field::Languages),
+            "format" | "formats" => Some(Field::Formats),
+            "comments" => Some(Field::Comments),
+            "identifier" | "identifiers" => Some(Field::Identifiers),
+            "rating" => Some(Field::Rating),
+            "pubdate" => Some(Field::Pubdate),
+            "date" => Some(Field::Timestamp),
+            "series_index" => Some(Field::SeriesIndex),
+            _ => None
+        }
+    }
+
+    /// `FROM ... WHERE ...` part of a sub-query selecting the values of a
+    /// multi-valued or linked field for the current book, together with the
+    /// column holding the value.
+    fn linked_source(&self) -> Option<(&'static str, &'static str)> {
+        match *self {
+            Field::Authors => Some(("
+FROM authors INNER JOIN books_authors_link
+  ON books_authors_link.author = authors.id
+WHERE books_authors_link.book = books.id", "authors.name")),
+            Field::Tags => Some(("
+FROM tags INNER JOIN books_tags_link
+  ON books_tags_link.tag = tags.id
+WHERE books_tags_link.book = books.id", "tags.name")),
+            Field::Series => Some(("
+FROM series INNER JOIN books_series_link
+  ON books_series_link.series = series.id
+WHERE books_series_link.book = books.id", "series.name")),
+            Field::Publisher => Some(("
+FROM publishers INNER JOIN books_publishers_link
+  ON books_publishers_link.publisher = publishers.id
+WHERE books_publishers_link.book = books.id", "publishers.name")),
+            Field::Languages => Some(("
+FROM languages INNER JOIN books_languages_link
+  ON books_languages_link.lang_code = languages.id
+WHERE books_languages_link.book = books.id", "languages.lang_code")),
+            Field::Formats => Some(("
+FROM data WHERE data.book = books.id", "data.format")),
+            Field::Comments => Some(("
+FROM comments WHERE comments.book = books.id", "comments.text")),
+            Field::Identifiers => Some(("
+FROM identifiers WHERE identifiers.book = books.id", "identifiers.val")),
+            _ => None
+        }
+    }
+}
+
+/// Fields searched by terms without an explicit field name.
+const DEFAULT_FIELDS: &[Field] = &[Field::Title, Field::Authors, Field::Series,
+                                   Field::Tags, Field::Publisher];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CmpOp {
+    Eq,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl CmpOp {
+    /// Splits a leading relational operator off `value`.
+    fn split(value: &str) -> (CmpOp, &str) {
+        for &(prefix, op) in &[(">=", CmpOp::Ge), ("<=", CmpOp::Le),
+                               (">", CmpOp::Gt), ("<", CmpOp::Lt),
+                               ("=", CmpOp::Eq)] {
+            if value.starts_with(prefix) {
+                return (op, &value[prefix.len()..]);
+            }
+        }
+        (CmpOp::Eq, value)
+    }
+
+    fn to_sql(&self) -> &'static str {
+        match *self {
+            CmpOp::Eq => "=",
+            CmpOp::Lt => "<",
+            CmpOp::Le => "<=",
+            CmpOp::Gt => ">",
+            CmpOp::Ge => ">=",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Matcher {
+    Contains(String),
+    Exact(String),
+    /// `field:true` / `field:false`
+    Exists(bool),
+    Number(CmpOp, f64),
+    /// Compares the leading `YYYY[-MM[-DD]]` part of a date, so that
+    /// `pubdate:>2010` means "after the year 2010".
+    Date(CmpOp, String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Match(Option<Field>, Matcher),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    LParen,
+    RParen,
+    And,
+    Or,
+    Not,
+    Term(Option<Field>, String),
+}
+
+fn read_quoted(chars: &mut Peekable<Chars>, text: &mut String)
+               -> Result<(), ParseError> {
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(()),
+            Some('\\') => match chars.next() {
+                Some(c) => text.push(c),
+                None => return Err(ParseError::new("unterminated quote"))
+            },
+            Some(c) => text.push(c),
+            None => return Err(ParseError::new("unterminated quote"))
+        }
+    }
+}
+
+fn tokenize(query: &str) -> Result<Vec<Token>, ParseError> {
+    let mut tokens = Vec::new();
+    let mut chars = query.chars().peekable();
+
+    loop {
+        while chars.peek().map_or(false, |c| c.is_whitespace()) {
+            chars.next();
+        }
+        match chars.peek().cloned() {
+            None => break,
+            Some('(') => {
+                chars.next();
+                tokens.push(Token::LParen);
+                continue;
+            },
+            Some(')') => {
+                chars.next();
+                tokens.push(Token::RParen);
+                continue;
+            },
+            Some(_) => {}
+        }
+
+        let mut text = String::new();
+        let mut field = None;
+        let mut quoted = false;
+        while let Some(c) = chars.peek().cloned() {
+            if c.is_whitespace() || c == '(' || c == ')' {
+                break;
+            }
+            chars.next();
+            if c == '"' {
+                quoted = true;
+                try!(read_quoted(&mut chars, &mut text));
+            } else if c == ':' && field.is_none() && !quoted {
+                // Unknown prefixes (e.g. in URLs) are kept as a part of the
+                // search term.
+                match Field::from_name(&text) {
+                    Some(f) => {
+                        field = Some(f);
+                        text.clear();
+                    },
+                    None => text.push(c)
+                }
+            } else {
+                text.push(c);
+            }
+        }
+
+        let token = if quoted || field.is_some() {
+            Token::Term(field, text)
+        } else {
+            match text.to_lowercase().as_str() {
+                "and" => Token::And,
+                "or" => Token::Or,
+                "not" => Token::Not,
+                _ => Token::Term(None, text)
+            }
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+fn is_date_prefix(s: &str) -> bool {
+    let bytes = s.as_bytes();
+    let valid_length = bytes.len() == 4 || bytes.len() == 7 || bytes.len() == 10;
+    valid_length && bytes.iter().enumerate().all(|(i, b)| {
+        if i == 4 || i == 7 { *b == b'-' } else { b.is_ascii_digit() }
+    })
+}
+
+fn make_matcher(field: Option<Field>, value: &str) -> Result<Matcher, ParseError> {
+    match value.to_lowercase().as_str() {
+        "true" if field.is_some() => return Ok(Matcher::Exists(true)),
+        "false" if field.is_some() => return Ok(Matcher::Exists(false)),
+        _ => {}
+    }
+
+    match field {
+        Some(Field::Rating) | Some(Field::SeriesIndex) => {
+            let (op, num) = CmpOp::split(value);
+            let num: f64 = try!(num.parse().map_err(
+                |_| ParseError::new(format!("not a number: {}", value))));
+            Ok(Matcher::Number(op, num))
+        },
+        Some(Field::Pubdate) | Some(Field::Timestamp) => {
+            let (op, date) = CmpOp::split(value);
+            if !is_date_prefix(date) {
+                return Err(ParseError::new(format!(
+                    "dates must be in YYYY[-MM[-DD]] form: {}", value)));
+            }
+            Ok(Matcher::Date(op, date.to_string()))
+        },
+        _ => {
+            if value.starts_with('~') {
+                Err(ParseError::new("regular expression search isn't supported"))
+            } else if value.starts_with('=') {
+                Ok(Matcher::Exact(value[1..].to_string()))
+            } else if value.is_empty() {
+                Err(ParseError::new("empty search term"))
+            } else {
+                Ok(Matcher::Contains(value.to_string()))
+            }
+        }
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, ParseError> {
+        let mut lhs = try!(self.parse_and());
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            let rhs = try!(self.parse_and());
+            lhs = Expr::Or(box lhs, box rhs);
+        }
+        Ok(lhs)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, ParseError> {
+        let mut lhs = try!(self.parse_not());
+        loop {
+            // Juxtaposed terms are implicitly joined by "and"
+            match self.peek().cloned() {
+                Some(Token::And) => { self.next(); },
+                Some(Token::Not) | Some(Token::LParen) | Some(Token::Term(_, _)) => {},
+                _ => break
+            }
+            let rhs = try!(self.parse_not());
+            lhs = Expr::And(box lhs, box rhs);
+        }
+        Ok(lhs)
+    }
+
+    fn parse_not(&mut self) -> Result<Expr, ParseError> {
+        if self.peek() == Some(&Token::Not) {
+            self.next();
+            let operand = try!(self.parse_not());
+            Ok(Expr::Not(box operand))
+        } else {
+            self.parse_primary()
+        }
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, ParseError> {
+        match self.next() {
+            Some(Token::LParen) => {
+                let expr = try!(self.parse_or());
+                match self.next() {
+                    Some(Token::RParen) => Ok(expr),
+                    _ => Err(ParseError::new("missing closing parenthesis"))
+                }
+            },
+            Some(Token::Term(field, value)) => {
+                let matcher = try!(make_matcher(field, &value));
+                Ok(Expr::Match(field, matcher))
+            },
+            Some(token) => Err(ParseError::new(format!("unexpected {:?}", token))),
+            None => Err(ParseError::new("unexpected end of query"))
+        }
+    }
+}
+
+/// Parses a Calibre search query.
+pub fn parse(query: &str) -> Result<Expr, ParseError> {
+    let tokens = try!(tokenize(query));
+    let mut parser = Parser { tokens: tokens, pos: 0 };
+    let expr = try!(parser.parse_or());
+    match parser.peek() {
+        None => Ok(expr),
+        Some(token) => Err(ParseError::new(format!("unexpected {:?}", token)))
+    }
+}
+
+fn escape_like(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('%');
+    for c in s.chars() {
+        if c == '%' || c == '_' || c == '\\' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped.push('%');
+    escaped
+}
+
+/// Pushes `value` to `params` and returns its placeholder.
+fn bind(params: &mut Vec<Value>, value: Value) -> String {
+    params.push(value);
+    format!("?{}", params.len())
+}
+
+fn text_condition(column: &str, matcher: &Matcher, params: &mut Vec<Value>)
+                  -> String {
+    match *matcher {
+        Matcher::Contains(ref s) => format!(
+            "{} LIKE {} ESCAPE '\\'",
+            column, bind(params, Value::Text(escape_like(s)))),
+        Matcher::Exact(ref s) => format!(
+            "{} = {} COLLATE NOCASE",
+            column, bind(params, Value::Text(s.clone()))),
+        _ => unreachable!()
+    }
+}
+
+fn identifier_condition(matcher: &Matcher, params: &mut Vec<Value>) -> String {
+    // "identifiers:isbn:123" matches the value of the given identifier type,
+    // "identifiers:isbn:" any book having an ISBN.
+    let (value, exact) = match *matcher {
+        Matcher::Contains(ref s) => (s, false),
+        Matcher::Exact(ref s) => (s, true),
+        _ => unreachable!()
+    };
+    let mut kv = value.splitn(2, ':');
+    let (id_type, id_val) = match (kv.next(), kv.next()) {
+        (Some(t), Some(v)) => (Some(t), v),
+        (Some(v), None) => (None, v),
+        _ => unreachable!()
+    };
+
+    let mut cond = String::from("EXISTS (SELECT 1 FROM identifiers
+WHERE identifiers.book = books.id");
+    if let Some(t) = id_type {
+        cond.push_str(&format!(
+            " AND identifiers.type = {} COLLATE NOCASE",
+            bind(params, Value::Text(t.to_string()))));
+    }
+    if !id_val.is_empty() {
+        let val_matcher = if exact {
+            Matcher::Exact(id_val.to_string())
+        } else {
+            Matcher::Contains(id_val.to_string())
+        };
+        cond.push_str(" AND ");
+        cond.push_str(&text_condition("identifiers.val", &val_matcher, params));
+    }
+    cond.push(')');
+    cond
+}
+
+fn field_condition(field: Field, matcher: &Matcher, params: &mut Vec<Value>)
+                   -> String {
+    match (field, matcher) {
+        (Field::Rating, &Matcher::Exists(exists)) => format!(
+            "coalesce((SELECT ratings.rating {}), 0) {} 0",
+            RATING_SOURCE, if exists { ">" } else { "=" }),
+        (Field::Rating, &Matcher::Number(op, stars)) => format!(
+            "(SELECT ratings.rating {}) {} {}",
+            RATING_SOURCE, op.to_sql(), bind(params, Value::Real(stars * 2.0))),
+        (Field::SeriesIndex, &Matcher::Exists(exists)) => format!(
+            "{}EXISTS (SELECT 1 FROM books_series_link
+WHERE books_series_link.book = books.id)", if exists { "" } else { "NOT " }),
+        (Field::SeriesIndex, &Matcher::Number(op, index)) => format!(
+            "books.series_index {} {}", op.to_sql(), bind(params, Value::Real(index))),
+        (Field::Pubdate, &Matcher::Exists(exists)) => format!(
+            "books.pubdate {}LIKE '0101-%'", if exists { "NOT " } else { "" }),
+        (Field::Pubdate, &Matcher::Date(op, ref date)) => format!(
+            "(books.pubdate NOT LIKE '0101-%' AND substr(books.pubdate, 1, {}) {} {})",
+            date.len(), op.to_sql(), bind(params, Value::Text(date.clone()))),
+        (Field::Timestamp, &Matcher::Exists(exists)) => format!(
+            "books.timestamp IS {}NULL", if exists { "NOT " } else { "" }),
+        (Field::Timestamp, &Matcher::Date(op, ref date)) => format!(
+            "substr(books.timestamp, 1, {}) {} {}",
+            date.len(), op.to_sql(), bind(params, Value::Text(date.clone()))),
+        (Field::Title, &Matcher::Exists(exists)) => format!(
+            "books.title {} ''", if exists { "!=" } else { "=" }),
+        (Field::Title, _) => text_condition("books.title", matcher, params),
+        (Field::Identifiers, &Matcher::Contains(_)) |
+        (Field::Identifiers, &Matcher::Exact(_)) =>
+            identifier_condition(matcher, params),
+        (_, &Matcher::Exists(exists)) => {
+            let (source, _) = field.linked_source().unwrap();
+            format!("{}EXISTS (SELECT 1 {})",
+                    if exists { "" } else { "NOT " }, source)
+        },
+        (_, _) => {
+            let (source, column) = field.linked_source().unwrap();
+            format!("EXISTS (SELECT 1 {} AND {})",
+                    source, text_condition(column, matcher, params))
+        }
+    }
+}
+
+const RATING_SOURCE: &str = "
+FROM ratings INNER JOIN books_ratings_link
+  ON books_ratings_link.rating = ratings.id
+WHERE books_ratings_link.book = books.id";
+
+impl Expr {
+    /// Compiles the expression into an SQL condition on `books`, appending
+    /// the values to be bound to `params`.
+    pub fn to_sql(&self, params: &mut Vec<Value>) -> String {
+        match *self {
+            Expr::And(ref lhs, ref rhs) =>
+                format!("({} AND {})", lhs.to_sql(params), rhs.to_sql(params)),
+            Expr::Or(ref lhs, ref rhs) =>
+                format!("({} OR {})", lhs.to_sql(params), rhs.to_sql(params)),
+            Expr::Not(ref operand) =>
+                format!("(NOT {})", operand.to_sql(params)),
+            Expr::Match(Some(field), ref matcher) =>
+                field_condition(field, matcher, params),
+            Expr::Match(None, ref matcher) => {
+                let conds: Vec<String> = DEFAULT_FIELDS.iter()
+                    .map(|f| field_condition(*f, matcher, params))
+                    .collect();
+                format!("({})", conds.join(" OR "))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn term(field: Option<Field>, s: &str) -> Expr {
+        Expr::Match(field, Matcher::Contains(s.to_string()))
+    }
+
+    #[test]
+    fn quoted_terms() {
+        assert_eq!(parse("title:\"the hobbit\"").unwrap(),
+                   term(Some(Field::Title), "the hobbit"));
+        assert_eq!(parse(r#""say \"hi\"""#).unwrap(), term(None, "say \"hi\""));
+        // Quoted keywords and parentheses are plain search terms.
+        assert_eq!(parse("\"and\"").unwrap(), term(None, "and"));
+        assert_eq!(parse("\"(a)\"").unwrap(), term(None, "(a)"));
+        assert!(parse("title:\"unterminated").is_err());
+        assert!(parse("\"trailing\\").is_err());
+    }
+
+    #[test]
+    fn negation_and_precedence() {
+        assert_eq!(parse("not tag:fantasy").unwrap(),
+                   Expr::Not(box term(Some(Field::Tags), "fantasy")));
+        assert_eq!(parse("NOT not a").unwrap(),
+                   Expr::Not(box Expr::Not(box term(None, "a"))));
+        assert_eq!(parse("a not b").unwrap(),
+                   Expr::And(box term(None, "a"), box Expr::Not(box term(None, "b"))));
+        assert_eq!(parse("a or b c").unwrap(),
+                   Expr::Or(box term(None, "a"),
+                            box Expr::And(box term(None, "b"), box term(None, "c"))));
+        assert_eq!(parse("not (a or b)").unwrap(),
+                   Expr::Not(box Expr::Or(box term(None, "a"), box term(None, "b"))));
+        assert!(parse("not").is_err());
+        assert!(parse("(a or b").is_err());
+        assert!(parse("a)").is_err());
+    }
+
+    #[test]
+    fn field_prefixes() {
+        assert_eq!(parse("Author:tolkien").unwrap(), term(Some(Field::Authors), "tolkien"));
+        assert_eq!(parse("tag:=sf").unwrap(),
+                   Expr::Match(Some(Field::Tags), Matcher::Exact("sf".to_string())));
+        assert_eq!(parse("series:true").unwrap(),
+                   Expr::Match(Some(Field::Series), Matcher::Exists(true)));
+        // Without a field, "true" is an ordinary word.
+        assert_eq!(parse("true").unwrap(), term(None, "true"));
+        // Unknown prefixes are kept as a part of the term.
+        assert_eq!(parse("http://example.com").unwrap(), term(None, "http://example.com"));
+        assert_eq!(parse("identifiers:isbn:123").unwrap(),
+                   term(Some(Field::Identifiers), "isbn:123"));
+        assert_eq!(parse("rating:>=4").unwrap(),
+                   Expr::Match(Some(Field::Rating), Matcher::Number(CmpOp::Ge, 4.0)));
+        assert_eq!(parse("pubdate:<2010-05").unwrap(),
+                   Expr::Match(Some(Field::Pubdate),
+                               Matcher::Date(CmpOp::Lt, "2010-05".to_string())));
+        assert!(parse("rating:many").is_err());
+        assert!(parse("pubdate:May").is_err());
+        assert!(parse("title:~regex").is_err());
+        assert!(parse("title:").is_err());
+    }
+
+    #[test]
+    fn like_escaping() {
+        assert_eq!(escape_like("plain"), "%plain%");
+        assert_eq!(escape_like(r"50%_off\"), r"%50\%\_off\\%");
+
+        let mut params = Vec::new();
+        let sql = parse("title:100%").unwrap().to_sql(&mut params);
+        assert_eq!(sql, "books.title LIKE ?1 ESCAPE '\\'");
+        assert_eq!(params, vec![Value::Text(r"%100\%%".to_string())]);
+    }
+
+    #[test]
+    fn sql_parameters() {
+        let mut params = Vec::new();
+        let sql = parse("tolkien").unwrap().to_sql(&mut params);
+        assert_eq!(params.len(), DEFAULT_FIELDS.len());
+        assert!(sql.starts_with("(books.title LIKE ?1 "));
+        assert!(sql.contains("?5"));
+
+        let mut params = Vec::new();
+        let sql = parse("title:=a or not rating:>3").unwrap().to_sql(&mut params);
+        assert!(sql.starts_with("(books.title = ?1 COLLATE NOCASE OR (NOT "));
+        assert_eq!(params, vec![Value::Text("a".to_string()), Value::Real(6.0)]);
+    }
+}
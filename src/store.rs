@@ -0,0 +1,175 @@
+//! Persistent state of weblibri itself, kept in an SQLite database in the
+//! cache dir (unlike `db`, which reads Calibre's metadata).
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use rusqlite::{Connection, Error};
+use serde_json;
+
+use cache::SourceFingerprint;
+use jobs::now;
+use worker::ConversionTask;
+
+const STORE_FILE: &str = "weblibri.db";
+
+/// How long to wait for another thread or process holding the database lock
+const BUSY_TIMEOUT_MS: u64 = 5000;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS conversion_failures (
+  book INTEGER PRIMARY KEY,
+  sources TEXT NOT NULL,
+  error TEXT NOT NULL,
+  failed_at INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS pending_tasks (
+  book INTEGER PRIMARY KEY,
+  task TEXT NOT NULL,
+  queued_at INTEGER NOT NULL
+);";
+
+/// A conversion that failed for reasons attributed to the book itself, e.g.
+/// DRM or a corrupted file.
+#[derive(Clone, Debug, Serialize)]
+pub struct ConversionFailure {
+    pub error: String,
+    /// Seconds since the epoch
+    pub failed_at: u64,
+    /// Source files that were tried
+    #[serde(skip)]
+    pub sources: Vec<SourceFingerprint>,
+}
+
+#[derive(Clone)]
+pub struct Store {
+    path: PathBuf,
+}
+
+impl Store {
+    /// Opens the store in the cache dir, creating it if needed.
+    pub fn open(cache_path: &Path) -> Result<Self, Error> {
+        let mut path = cache_path.to_path_buf();
+        path.push(STORE_FILE);
+        let store = Store { path: path };
+        try!(try!(store.connect()).execute_batch(SCHEMA));
+        Ok(store)
+    }
+
+    /// Connections aren't shareable between threads, so each operation opens
+    /// its own one.
+    fn connect(&self) -> Result<Connection, Error> {
+        let conn = try!(Connection::open(&self.path));
+        try!(conn.busy_timeout(Duration::from_millis(BUSY_TIMEOUT_MS)));
+        Ok(conn)
+    }
+
+    pub fn record_failure(&self, bookid: i64, sources: &[SourceFingerprint],
+                          error: &str) -> Result<(), Error> {
+        let sources = match serde_json::to_string(sources) {
+            Ok(sources) => sources,
+            Err(e) => {
+                // e.g. non UTF-8 paths; the book will just be retried.
+                warn!("Can't record the sources of book {}: {}", bookid, e);
+                return Ok(());
+            }
+        };
+        let failed_at = now() as i64;
+        try!(self.connect()).execute("
+INSERT OR REPLACE INTO conversion_failures (book, sources, error, failed_at)
+ VALUES (?, ?, ?, ?)", &[&bookid, &sources, &error, &failed_at]).map(|_| ())
+    }
+
+    /// Returns the recorded failure of the book if it was for exactly the
+    /// given sources; failures of outdated sources don't count.
+    pub fn get_failure(&self, bookid: i64, sources: &[SourceFingerprint])
+                       -> Result<Option<ConversionFailure>, Error> {
+        let conn = try!(self.connect());
+        let mut stmt = try!(conn.prepare("
+SELECT sources, error, failed_at FROM conversion_failures WHERE book = ?"));
+        let mut rows = try!(stmt.query(&[&bookid]));
+        let row = match rows.next() {
+            Some(row) => try!(row),
+            None => return Ok(None)
+        };
+        let recorded: String = row.get(0);
+        let failed_at: i64 = row.get(2);
+        let failure = ConversionFailure {
+            error: row.get(1),
+            failed_at: failed_at as u64,
+            sources: serde_json::from_str(&recorded).unwrap_or_default(),
+        };
+        Ok(if failure.sources.as_slice() == sources { Some(failure) } else { None })
+    }
+
+    /// Forgets the failure of the book so that it will be converted again.
+    /// Returns false if there was none.
+    pub fn clear_failure(&self, bookid: i64) -> Result<bool, Error> {
+        try!(self.connect()).execute(
+            "DELETE FROM conversion_failures WHERE book = ?", &[&bookid])
+            .map(|n| n > 0)
+    }
+
+    /// Forgets all failures, returning how many there were.
+    pub fn clear_all_failures(&self) -> Result<usize, Error> {
+        try!(self.connect()).execute("DELETE FROM conversion_failures", &[])
+            .map(|n| n as usize)
+    }
+
+    /// Persists a queued task so that it can be resumed after a restart.
+    pub fn save_task(&self, task: &ConversionTask) -> Result<(), Error> {
+        let json = match serde_json::to_string(task) {
+            Ok(json) => json,
+            Err(e) => {
+                warn!("Can't persist the task of book {}: {}", task.bookid, e);
+                return Ok(());
+            }
+        };
+        let queued_at = now() as i64;
+        try!(self.connect()).execute("
+INSERT OR REPLACE INTO pending_tasks (book, task, queued_at) VALUES (?, ?, ?)",
+                                     &[&task.bookid, &json, &queued_at]).map(|_| ())
+    }
+
+    /// Removes the persisted task of the book if it's of the given job
+    /// generation, leaving tasks of later jobs alone.
+    pub fn remove_task(&self, bookid: i64, generation: u64) -> Result<(), Error> {
+        let mut conn = try!(self.connect());
+        let tx = try!(conn.transaction());
+        let json: Option<String> = match tx.query_row(
+            "SELECT task FROM pending_tasks WHERE book = ?", &[&bookid],
+            |row| row.get(0)) {
+            Ok(json) => Some(json),
+            Err(Error::QueryReturnedNoRows) => None,
+            Err(e) => return Err(e)
+        };
+        let stored_generation = json.and_then(|json| {
+            serde_json::from_str::<ConversionTask>(&json).ok()
+        }).map(|task| task.generation);
+        // Unreadable tasks are removed too; they can't be resumed anyway.
+        if stored_generation.map(|g| g == generation).unwrap_or(true) {
+            try!(tx.execute("DELETE FROM pending_tasks WHERE book = ?", &[&bookid]));
+        }
+        tx.commit()
+    }
+
+    /// Tasks that were queued but not finished, oldest first.
+    pub fn pending_tasks(&self) -> Result<Vec<ConversionTask>, Error> {
+        let conn = try!(self.connect());
+        let mut stmt = try!(conn.prepare(
+            "SELECT book, task FROM pending_tasks ORDER BY queued_at, book"));
+        let mut rows = try!(stmt.query(&[]));
+        let mut tasks = Vec::new();
+        while let Some(row) = rows.next() {
+            let row = try!(row);
+            let bookid: i64 = row.get(0);
+            let json: String = row.get(1);
+            match serde_json::from_str(&json) {
+                Ok(task) => tasks.push(task),
+                Err(e) => warn!("Ignoring the unreadable pending task of book {}: {}",
+                                bookid, e)
+            }
+        }
+        Ok(tasks)
+    }
+}
@@ -0,0 +1,621 @@
+//! Readium Web Publication Manifests generated from the OPF of books
+//! extracted in the cache dir, and an OPDS 2.0 catalog of those books.
+
+use std::{io, fmt, fs};
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use actix_web::{HttpRequest, HttpResponse};
+use actix_web::http::StatusCode;
+use serde_json;
+use xml;
+use xml::attribute::OwnedAttribute;
+use xml::reader::{EventReader, XmlEvent};
+
+use cache::check_cache_availability;
+use db::{Book, BookList};
+use httphandler::{AppState, thumbnail_uri};
+use worker::safe_entry_path;
+
+const CONTAINER_FILE: &str = "META-INF/container.xml";
+
+const WEBPUB_TYPE: &str = "application/webpub+json";
+const OPDS2_TYPE: &str = "application/opds+json";
+
+/// Number of publications in a page of the OPDS 2.0 catalog
+const PAGE_SIZE: usize = 50;
+
+/// Size of thumbnails advertised in the catalog
+const CATALOG_THUMBNAIL_SIZE: u32 = 128;
+
+#[derive(Debug)]
+pub enum WebPubError {
+    IoError(io::Error),
+    XmlError(xml::reader::Error),
+    InvalidPackage(String),
+}
+use self::WebPubError::{IoError, XmlError, InvalidPackage};
+
+impl Error for WebPubError {
+    fn description(&self) -> &str {
+        "web publication error"
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match self {
+            IoError(e) => Some(e),
+            XmlError(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for WebPubError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IoError(e) => write!(f, "Failed to read package: {}", e),
+            XmlError(e) => write!(f, "Malformed XML in package: {}", e),
+            InvalidPackage(msg) => write!(f, "Invalid package: {}", msg),
+        }
+    }
+}
+
+impl From<io::Error> for WebPubError {
+    fn from(e: io::Error) -> Self {
+        IoError(e)
+    }
+}
+
+impl From<xml::reader::Error> for WebPubError {
+    fn from(e: xml::reader::Error) -> Self {
+        XmlError(e)
+    }
+}
+
+#[derive(Serialize, Default)]
+pub struct Link {
+    href: String,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    mime: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rel: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    children: Vec<Link>,
+}
+
+#[derive(Serialize)]
+pub struct Metadata {
+    #[serde(rename = "@type")]
+    kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    identifier: Option<String>,
+    title: String,
+    author: Vec<String>,
+    language: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    published: Option<String>,
+    #[serde(rename = "readingProgression")]
+    reading_progression: String,
+}
+
+#[derive(Serialize)]
+pub struct Manifest {
+    #[serde(rename = "@context")]
+    context: &'static str,
+    metadata: Metadata,
+    links: Vec<Link>,
+    #[serde(rename = "readingOrder")]
+    reading_order: Vec<Link>,
+    resources: Vec<Link>,
+    toc: Vec<Link>,
+}
+
+struct ManifestItem {
+    id: String,
+    href: String,
+    mime: String,
+    properties: String,
+}
+
+fn attr<'a>(attributes: &'a [OwnedAttribute], name: &str) -> Option<&'a str> {
+    attributes.iter()
+        .find(|a| a.name.local_name == name)
+        .map(|a| a.value.as_str())
+}
+
+/// Resolves `href` relative to the directory `base`, both being '/'-separated
+/// paths inside the publication.
+fn resolve_href(base: &str, href: &str) -> String {
+    let mut segments: Vec<&str> = base.split('/').filter(|s| !s.is_empty()).collect();
+    for seg in href.split('/') {
+        match seg {
+            "" | "." => {},
+            ".." => { segments.pop(); },
+            _ => segments.push(seg)
+        }
+    }
+    segments.join("/")
+}
+
+fn parent_dir(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(pos) => &path[..pos],
+        None => ""
+    }
+}
+
+/// Decodes %XX escapes in an href into a file path.
+fn percent_decode(href: &str) -> String {
+    let bytes = href.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = if bytes[i] == b'%' && i + 2 < bytes.len() {
+            ::std::str::from_utf8(&bytes[i + 1..i + 3]).ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        } else {
+            None
+        };
+        match escaped {
+            Some(b) => {
+                decoded.push(b);
+                i += 3;
+            },
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn open_xml(book_path: &Path, href: &str) -> Result<EventReader<BufReader<File>>, WebPubError> {
+    // hrefs come from the book, and mustn't point outside of it once decoded.
+    let relpath = match safe_entry_path(&percent_decode(href)) {
+        Some(relpath) => relpath,
+        None => return Err(InvalidPackage(format!("unsafe path {:?}", href)))
+    };
+    let mut path = book_path.to_path_buf();
+    path.push(relpath);
+    Ok(EventReader::new(BufReader::new(try!(File::open(path)))))
+}
+
+/// Path of the OPF file referenced by `META-INF/container.xml`.
+fn find_package_path(book_path: &Path) -> Result<String, WebPubError> {
+    for e in try!(open_xml(book_path, CONTAINER_FILE)) {
+        if let XmlEvent::StartElement { name, attributes, .. } = try!(e) {
+            if name.local_name == "rootfile" {
+                if let Some(path) = attr(&attributes, "full-path") {
+                    return Ok(path.to_string());
+                }
+            }
+        }
+    }
+    Err(InvalidPackage(String::from("no rootfile in container.xml")))
+}
+
+/// Parses an EPUB 2 NCX document into a TOC.
+fn parse_ncx(book_path: &Path, ncx_href: &str, base_uri: &str)
+             -> Result<Vec<Link>, WebPubError> {
+    let ncx_dir = parent_dir(ncx_href);
+    let mut roots = Vec::new();
+    let mut stack: Vec<Link> = Vec::new();
+    let mut in_label = false;
+
+    for e in try!(open_xml(book_path, ncx_href)) {
+        match try!(e) {
+            XmlEvent::StartElement { name, attributes, .. } => {
+                match name.local_name.as_str() {
+                    "navPoint" => stack.push(Link::default()),
+                    "navLabel" => in_label = true,
+                    "content" => if let (Some(top), Some(src)) =
+                        (stack.last_mut(), attr(&attributes, "src")) {
+                            top.href = format!("{}/{}", base_uri, resolve_href(ncx_dir, src));
+                        },
+                    _ => {}
+                }
+            },
+            XmlEvent::Characters(text) => if in_label {
+                if let Some(top) = stack.last_mut() {
+                    top.title.get_or_insert_with(String::new).push_str(text.trim());
+                }
+            },
+            XmlEvent::EndElement { name } => {
+                match name.local_name.as_str() {
+                    "navLabel" => in_label = false,
+                    "navPoint" => {
+                        let link = stack.pop().unwrap();
+                        match stack.last_mut() {
+                            Some(parent) => parent.children.push(link),
+                            None => roots.push(link)
+                        }
+                    },
+                    _ => {}
+                }
+            },
+            _ => {}
+        }
+    }
+    Ok(roots)
+}
+
+/// Parses the `<nav epub:type="toc">` of an EPUB 3 navigation document.
+fn parse_nav(book_path: &Path, nav_href: &str, base_uri: &str)
+             -> Result<Vec<Link>, WebPubError> {
+    let nav_dir = parent_dir(nav_href);
+    let mut roots = Vec::new();
+    let mut stack: Vec<Link> = Vec::new();
+    let mut in_toc = false;
+    let mut in_label = false;
+
+    for e in try!(open_xml(book_path, nav_href)) {
+        match try!(e) {
+            XmlEvent::StartElement { name, attributes, .. } => {
+                match name.local_name.as_str() {
+                    "nav" => in_toc = attr(&attributes, "type") == Some("toc"),
+                    "li" if in_toc => stack.push(Link::default()),
+                    "a" | "span" if in_toc => {
+                        in_label = true;
+                        if let (Some(top), Some(href)) =
+                            (stack.last_mut(), attr(&attributes, "href")) {
+                                top.href = format!("{}/{}", base_uri, resolve_href(nav_dir, href));
+                            }
+                    },
+                    _ => {}
+                }
+            },
+            XmlEvent::Characters(text) => if in_label {
+                if let Some(top) = stack.last_mut() {
+                    let title = top.title.get_or_insert_with(String::new);
+                    if !title.is_empty() {
+                        title.push(' ');
+                    }
+                    title.push_str(text.trim());
+                }
+            },
+            XmlEvent::EndElement { name } => {
+                match name.local_name.as_str() {
+                    "nav" => in_toc = false,
+                    "a" | "span" => in_label = false,
+                    "li" if in_toc => {
+                        let link = stack.pop().unwrap();
+                        match stack.last_mut() {
+                            Some(parent) => parent.children.push(link),
+                            None => roots.push(link)
+                        }
+                    },
+                    _ => {}
+                }
+            },
+            _ => {}
+        }
+    }
+    Ok(roots)
+}
+
+/// Generates the manifest of the book extracted to `book_path`, whose files
+/// are served under `base_uri`.
+pub fn build_manifest(book_path: &Path, base_uri: &str, self_uri: &str)
+                      -> Result<Manifest, WebPubError> {
+    let package_path = try!(find_package_path(book_path));
+    let package_dir = parent_dir(&package_path).to_string();
+
+    let mut metadata = Metadata {
+        kind: "http://schema.org/Book",
+        identifier: None,
+        title: String::new(),
+        author: Vec::new(),
+        language: Vec::new(),
+        published: None,
+        reading_progression: String::from("auto"),
+    };
+    let mut unique_id_ref = None;
+    let mut items: Vec<ManifestItem> = Vec::new();
+    let mut spine: Vec<String> = Vec::new();
+    let mut ncx_id = None;
+
+    // Text content is collected for the innermost open metadata element.
+    let mut current: Option<(String, Option<String>)> = None;
+    let mut text = String::new();
+
+    for e in try!(open_xml(book_path, &package_path)) {
+        match try!(e) {
+            XmlEvent::StartElement { name, attributes, .. } => {
+                match name.local_name.as_str() {
+                    "package" => {
+                        unique_id_ref = attr(&attributes, "unique-identifier")
+                            .map(|s| s.to_string());
+                    },
+                    "title" | "creator" | "language" | "identifier" | "date" => {
+                        current = Some((name.local_name.clone(),
+                                        attr(&attributes, "id").map(|s| s.to_string())));
+                        text.clear();
+                    },
+                    "item" => {
+                        items.push(ManifestItem {
+                            id: attr(&attributes, "id").unwrap_or("").to_string(),
+                            href: attr(&attributes, "href").unwrap_or("").to_string(),
+                            mime: attr(&attributes, "media-type").unwrap_or("").to_string(),
+                            properties: attr(&attributes, "properties").unwrap_or("").to_string(),
+                        });
+                    },
+                    "spine" => {
+                        ncx_id = attr(&attributes, "toc").map(|s| s.to_string());
+                        if let Some(dir) = attr(&attributes, "page-progression-direction") {
+                            metadata.reading_progression = dir.to_string();
+                        }
+                    },
+                    "itemref" => {
+                        if attr(&attributes, "linear") != Some("no") {
+                            if let Some(idref) = attr(&attributes, "idref") {
+                                spine.push(idref.to_string());
+                            }
+                        }
+                    },
+                    _ => {}
+                }
+            },
+            XmlEvent::Characters(s) => if current.is_some() {
+                text.push_str(&s);
+            },
+            XmlEvent::EndElement { name } => {
+                let is_current = current.as_ref()
+                    .map_or(false, |&(ref n, _)| *n == name.local_name);
+                if is_current {
+                    let (elem, id) = current.take().unwrap();
+                    let value = text.trim().to_string();
+                    match elem.as_str() {
+                        "title" if metadata.title.is_empty() => metadata.title = value,
+                        "creator" => metadata.author.push(value),
+                        "language" => metadata.language.push(value),
+                        "date" if metadata.published.is_none() =>
+                            metadata.published = Some(value),
+                        "identifier" => {
+                            if metadata.identifier.is_none() || id == unique_id_ref {
+                                metadata.identifier = Some(value);
+                            }
+                        },
+                        _ => {}
+                    }
+                }
+            },
+            _ => {}
+        }
+    }
+
+    let item_link = |item: &ManifestItem| Link {
+        href: format!("{}/{}", base_uri, resolve_href(&package_dir, &item.href)),
+        mime: Some(item.mime.clone()),
+        ..Link::default()
+    };
+    let reading_order: Vec<Link> = spine.iter()
+        .filter_map(|idref| items.iter().find(|item| item.id == *idref))
+        .map(&item_link)
+        .collect();
+    let resources: Vec<Link> = items.iter()
+        .filter(|item| !spine.contains(&item.id))
+        .map(&item_link)
+        .collect();
+    if reading_order.is_empty() {
+        return Err(InvalidPackage(String::from("empty spine")));
+    }
+
+    // Prefer the EPUB 3 navigation document, but fall back to the NCX since
+    // the former is often not well-formed XML (e.g. HTML entities).
+    let nav_item = items.iter()
+        .find(|item| item.properties.split_whitespace().any(|p| p == "nav"));
+    let ncx_item = ncx_id.and_then(|id| items.iter().find(|item| item.id == id));
+    let mut toc = Vec::new();
+    if let Some(nav) = nav_item {
+        match parse_nav(book_path, &resolve_href(&package_dir, &nav.href), base_uri) {
+            Ok(t) => toc = t,
+            Err(e) => warn!("Failed to parse navigation document in {:?}: {}",
+                            book_path, e)
+        }
+    }
+    if toc.is_empty() {
+        if let Some(ncx) = ncx_item {
+            match parse_ncx(book_path, &resolve_href(&package_dir, &ncx.href), base_uri) {
+                Ok(t) => toc = t,
+                Err(e) => warn!("Failed to parse NCX in {:?}: {}", book_path, e)
+            }
+        }
+    }
+
+    Ok(Manifest {
+        context: "https://readium.org/webpub-manifest/context.jsonld",
+        metadata: metadata,
+        links: vec![Link {
+            href: self_uri.to_string(),
+            mime: Some(WEBPUB_TYPE.to_string()),
+            rel: Some(String::from("self")),
+            ..Link::default()
+        }],
+        reading_order: reading_order,
+        resources: resources,
+        toc: toc,
+    })
+}
+
+fn manifest_uri(app_prefix: &str, bookid: i64) -> String {
+    format!("{}/api/{}/manifest.json", app_prefix, bookid)
+}
+
+pub fn get_manifest(req: &HttpRequest<AppState>) -> HttpResponse {
+    let bookid: i64 =
+        req.match_info().get("bookid").unwrap().parse().unwrap();
+    let app_prefix = &req.state().app_prefix;
+
+    let mut reader_path = req.state().cache_path.clone();
+    reader_path.push(format!("{}", bookid));
+    if ! check_cache_availability(&reader_path) {
+        return HttpResponse::new(StatusCode::NOT_FOUND);
+    }
+
+    let base_uri = format!("{}/book/{}", app_prefix, bookid);
+    match build_manifest(&reader_path, &base_uri, &manifest_uri(app_prefix, bookid)) {
+        Ok(manifest) => HttpResponse::Ok()
+            .content_type(WEBPUB_TYPE)
+            .body(serde_json::to_string(&manifest).unwrap()),
+        Err(e) => {
+            warn!("Failed to build manifest of book {}: {}", bookid, e);
+            HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct PublicationMetadata {
+    #[serde(rename = "@type")]
+    kind: &'static str,
+    identifier: String,
+    title: String,
+    author: Vec<String>,
+    language: Vec<String>,
+    modified: String,
+}
+
+#[derive(Serialize)]
+struct Publication {
+    metadata: PublicationMetadata,
+    links: Vec<Link>,
+    images: Vec<Link>,
+}
+
+#[derive(Serialize)]
+struct CatalogMetadata {
+    title: &'static str,
+    #[serde(rename = "numberOfItems")]
+    number_of_items: usize,
+}
+
+#[derive(Serialize)]
+struct Catalog {
+    metadata: CatalogMetadata,
+    links: Vec<Link>,
+    publications: Vec<Publication>,
+}
+
+/// IDs of books completely extracted in the cache dir.
+fn cached_book_ids(cache_path: &PathBuf) -> io::Result<Vec<i64>> {
+    let mut ids = Vec::new();
+    for entry in try!(fs::read_dir(cache_path)) {
+        let entry = try!(entry);
+        let id = entry.file_name().to_str().and_then(|s| s.parse().ok());
+        if let Some(id) = id {
+            if check_cache_availability(&entry.path()) {
+                ids.push(id);
+            }
+        }
+    }
+    ids.sort();
+    Ok(ids)
+}
+
+fn publication(app_prefix: &str, book: &Book) -> Publication {
+    Publication {
+        metadata: PublicationMetadata {
+            kind: "http://schema.org/Book",
+            identifier: format!("urn:uuid:{}", book.uuid),
+            title: book.title.clone(),
+            author: book.authors.clone(),
+            language: book.languages.clone(),
+            modified: book.last_modified.replacen(' ', "T", 1),
+        },
+        links: vec![Link {
+            href: manifest_uri(app_prefix, book.id),
+            mime: Some(WEBPUB_TYPE.to_string()),
+            rel: Some(String::from("http://opds-spec.org/acquisition")),
+            ..Link::default()
+        }],
+        images: thumbnail_uri(app_prefix, book, CATALOG_THUMBNAIL_SIZE)
+            .map(|uri| vec![Link {
+                href: uri,
+                mime: Some(String::from("image/jpeg")),
+                ..Link::default()
+            }])
+            .unwrap_or_default(),
+    }
+}
+
+pub fn get_catalog(req: &HttpRequest<AppState>) -> HttpResponse {
+    let app_prefix = &req.state().app_prefix;
+    let offset: usize =
+        req.query().get("offset").and_then(|s| s.parse().ok()).unwrap_or(0);
+
+    let ids = match cached_book_ids(&req.state().cache_path) {
+        Ok(ids) => ids,
+        Err(e) => {
+            warn!("Failed to list the cache dir: {}", e);
+            return HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    let conn = req.state().get_meta_data_conn();
+    let booklist = BookList::new(&conn);
+    let publications = ids.iter().skip(offset).take(PAGE_SIZE)
+        .filter_map(|id| booklist.get(*id))
+        .map(|book| publication(app_prefix, &book))
+        .collect();
+
+    let catalog_uri = format!("{}/opds/v2/catalog.json", app_prefix);
+    let mut links = vec![Link {
+        href: format!("{}?offset={}", catalog_uri, offset),
+        mime: Some(OPDS2_TYPE.to_string()),
+        rel: Some(String::from("self")),
+        ..Link::default()
+    }];
+    if offset + PAGE_SIZE < ids.len() {
+        links.push(Link {
+            href: format!("{}?offset={}", catalog_uri, offset + PAGE_SIZE),
+            mime: Some(OPDS2_TYPE.to_string()),
+            rel: Some(String::from("next")),
+            ..Link::default()
+        });
+    }
+
+    let catalog = Catalog {
+        metadata: CatalogMetadata {
+            title: "Weblibri",
+            number_of_items: ids.len(),
+        },
+        links: links,
+        publications: publications,
+    };
+    HttpResponse::Ok()
+        .content_type(OPDS2_TYPE)
+        .body(serde_json::to_string(&catalog).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::open_xml;
+    use super::WebPubError::InvalidPackage;
+    use std::{env, fs, process};
+
+    #[test]
+    fn hrefs_stay_in_book() {
+        let root = env::temp_dir().join(format!("weblibri-webpub-{}", process::id()));
+        let book = root.join("book");
+        fs::create_dir_all(book.join("OEBPS")).unwrap();
+        fs::write(book.join("OEBPS/toc.ncx"), "<ncx/>").unwrap();
+        fs::write(root.join("secret.xml"), "<secret/>").unwrap();
+
+        assert!(open_xml(&book, "OEBPS/toc.ncx").is_ok());
+        assert!(open_xml(&book, "OEBPS%2Ftoc.ncx").is_ok());
+        for href in &["../secret.xml", "OEBPS/../../secret.xml", "%2e%2e/secret.xml",
+                      "..%2Fsecret.xml", "/etc/passwd"] {
+            match open_xml(&book, href) {
+                Err(InvalidPackage(_)) => {},
+                Err(e) => panic!("{}: unexpected error {}", href, e),
+                Ok(_) => panic!("{}: opened outside of the book", href),
+            }
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
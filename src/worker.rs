@@ -1,22 +1,248 @@
-use std::{io, fmt};
-use std::path::PathBuf;
+use std::{io, fmt, fs};
+use std::fs::File;
+use std::path::{Component, Path, PathBuf};
 use std::error::Error;
-use std::sync::mpsc::Receiver;
-use std::process::{Command, ExitStatus};
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::{Arc, Mutex, MutexGuard, Condvar, PoisonError};
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::time::{Duration, Instant};
+use std::process::ExitStatus;
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::thread;
 
-use cache::check_cache_availability;
+use rusqlite::Connection;
+use zip::ZipArchive;
+use zip::result::ZipError;
+
+use cache::{check_cache_availability, check_cache_freshness, dir_size, staging_path,
+            CacheManager, SourceFingerprint};
+use jobs::{now, JobRegistry, JobState};
+use store::Store;
+use db::{BookList, ConversionOptions};
+use converter::{ConversionLog, Converters, JobControl};
+
+/// Delay before restarting a worker that panicked, doubled on each panic in
+/// a row up to the maximum. A worker that ran longer than the maximum before
+/// panicking starts over from the minimum.
+const MIN_RESTART_DELAY_MS: u64 = 1000;
+const MAX_RESTART_DELAY_MS: u64 = 60000;
+
+/// List of all supported formats in the preference order
+const PREFERRED_FORMAT: &[&'static str] = &["EPUB", "HTMLZ", "AZW3", "AZW4", "MOBI", "PDF"];
+
+/// Lists the source files of the book to make the reader copy from, in order
+/// of preference. Files missing from the data dir are left out.
+pub fn find_sources(conn: &Connection, data_path: &Path, bookid: i64)
+                    -> Vec<SourceFingerprint> {
+    let mut stmt = conn.prepare("
+SELECT books.path, data.name, data.format, books.last_modified
+FROM books INNER JOIN data
+WHERE data.book = books.id AND books.id = (:bookid)").unwrap();
+
+    let mut rows = stmt.query_named(&[(":bookid", &bookid)]).unwrap();
+
+    let mut sources: Vec<(usize, SourceFingerprint)> = Vec::new();
+    while let Some(result_row) = rows.next() {
+        let row = result_row.unwrap();
+        let format: String = row.get(2);
+        let cost =
+            PREFERRED_FORMAT.iter().position(|x| *x == format)
+            .unwrap_or(PREFERRED_FORMAT.len());
+        let dirname: String = row.get(0);
+        let mut filename: String = row.get(1);
+        filename.push('.');
+        filename.push_str(&format.to_lowercase());
+
+        let mut src_path = data_path.to_path_buf();
+        src_path.push(dirname);
+        src_path.push(filename);
+        let last_modified: String = row.get(3);
+        match SourceFingerprint::of(&src_path, &last_modified) {
+            Ok(source) => sources.push((cost, source)),
+            Err(e) => warn!("Source file {:?} of book {} is unavailable: {}",
+                            src_path, bookid, e)
+        }
+    }
+    sources.sort_by_key(|&(cost, _)| cost);
+    sources.into_iter().map(|(_, source)| source).collect()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Priority {
+    /// e.g. pre-conversion of books nobody is waiting for
+    Background,
+    /// A user is waiting for the book in the convert modal
+    Interactive,
+}
+
+/// A request to make the reader copy of a book at `dest` from the first of
+/// `sources` that converts successfully.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ConversionTask {
+    pub bookid: i64,
+    pub dest: PathBuf,
+    /// Source files of the book in order of preference
+    pub sources: Vec<SourceFingerprint>,
+    /// Calibre's per-book conversion settings
+    pub options: ConversionOptions,
+    pub priority: Priority,
+    /// Generation of the job the task was queued for
+    #[serde(default)]
+    pub generation: u64,
+}
+
+/// Queued task ordered by priority first, then by arrival.
+struct QueuedTask {
+    task: ConversionTask,
+    seq: u64,
+}
+
+impl PartialEq for QueuedTask {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for QueuedTask {}
+
+impl PartialOrd for QueuedTask {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for QueuedTask {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.task.priority.cmp(&other.task.priority)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+struct QueueState {
+    heap: BinaryHeap<QueuedTask>,
+    next_seq: u64,
+}
+
+struct QueueInner {
+    state: Mutex<QueueState>,
+    not_empty: Condvar,
+    capacity: usize,
+}
+
+/// Bounded priority queue of conversion tasks shared by the worker pool.
+#[derive(Clone)]
+pub struct TaskQueue {
+    inner: Arc<QueueInner>,
+}
+
+impl TaskQueue {
+    pub fn new(capacity: usize) -> Self {
+        TaskQueue {
+            inner: Arc::new(QueueInner {
+                state: Mutex::new(QueueState {
+                    heap: BinaryHeap::new(),
+                    next_seq: 0,
+                }),
+                not_empty: Condvar::new(),
+                capacity: capacity,
+            })
+        }
+    }
+
+    /// The heap is never left half-updated, so it's still usable after a
+    /// panic while it was locked.
+    fn state(&self) -> MutexGuard<QueueState> {
+        self.inner.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Enqueues a task, or gives it back if the queue is full so that the
+    /// caller can reject the request instead of blocking.
+    pub fn try_push(&self, task: ConversionTask) -> Result<(), ConversionTask> {
+        let mut state = self.state();
+        if state.heap.len() >= self.inner.capacity {
+            return Err(task);
+        }
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.heap.push(QueuedTask { task: task, seq: seq });
+        self.inner.not_empty.notify_one();
+        Ok(())
+    }
+
+    /// Dequeues the task with the highest priority, blocking while the queue
+    /// is empty.
+    pub fn pop(&self) -> ConversionTask {
+        let mut state = self.state();
+        loop {
+            if let Some(queued) = state.heap.pop() {
+                return queued.task;
+            }
+            state = self.inner.not_empty.wait(state)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    /// Dequeues the task with the highest priority, if any, without blocking.
+    pub fn try_pop(&self) -> Option<ConversionTask> {
+        self.state().heap.pop().map(|queued| queued.task)
+    }
+
+    /// Drops the queued task of the given job of the book, if any, leaving
+    /// tasks of later jobs alone. Returns false if there was none, e.g.
+    /// because a worker has already taken it.
+    pub fn remove(&self, bookid: i64, generation: u64) -> bool {
+        let mut state = self.state();
+        let before = state.heap.len();
+        let tasks = ::std::mem::replace(&mut state.heap, BinaryHeap::new()).into_vec();
+        state.heap = tasks.into_iter()
+            .filter(|queued| {
+                queued.task.bookid != bookid || queued.task.generation != generation
+            })
+            .collect();
+        state.heap.len() < before
+    }
+
+    /// Number of tasks waiting for a worker
+    pub fn len(&self) -> usize {
+        self.state().heap.len()
+    }
+
+    /// Raises the priority of the queued task of the book, if any.
+    pub fn promote(&self, bookid: i64, priority: Priority) {
+        let mut state = self.state();
+        let mut tasks = ::std::mem::replace(&mut state.heap, BinaryHeap::new()).into_vec();
+        for queued in tasks.iter_mut() {
+            if queued.task.bookid == bookid && queued.task.priority < priority {
+                debug!("Raising priority of the task for book {}", bookid);
+                queued.task.priority = priority;
+            }
+        }
+        state.heap = BinaryHeap::from(tasks);
+    }
+}
 
 #[derive(Debug)]
-enum ConversionError {
+pub enum ConversionError {
     EpubConversionCommandError(io::Error),
     EpubConversionError(ExitStatus),
-    EpubDeflationCommandError(io::Error),
-    EpubDeflationError(ExitStatus),
-    CleanUpError(io::Error)
+    NativeConversionError(String),
+    TimeoutError(Duration),
+    CancelledError,
+    EpubExtractionError(ZipError),
+    UnsafeEntryError(String),
+    InvalidEpubError,
+    CacheWriteError(io::Error),
+    CleanUpError(io::Error),
+    /// Errors of each source format that was tried
+    NoUsableSourceError(Vec<(String, ConversionError)>)
 }
 use self::ConversionError::{EpubConversionCommandError,EpubConversionError,
-                            EpubDeflationCommandError,EpubDeflationError,
-                            CleanUpError};
+                            NativeConversionError,EpubExtractionError,
+                            UnsafeEntryError,InvalidEpubError,CacheWriteError,
+                            CleanUpError,NoUsableSourceError,TimeoutError,
+                            CancelledError};
 
 impl Error for ConversionError {
     fn description(&self) -> &str {
@@ -26,7 +252,8 @@ impl Error for ConversionError {
     fn cause(&self) -> Option<&Error> {
         match self {
             EpubConversionCommandError(e) => Some(e),
-            EpubDeflationCommandError(e) => Some(e),
+            EpubExtractionError(e) => Some(e),
+            CacheWriteError(e) => Some(e),
             CleanUpError(e) => Some(e),
             _ => None
         }
@@ -40,94 +267,661 @@ impl fmt::Display for ConversionError {
                 write!(f, "Failed to launch converter: {}", e),
             EpubConversionError(code) =>
                 write!(f, "Converter exited with an error code: {:?}", code),
-            EpubDeflationCommandError(e) =>
-                write!(f, "Failed to launch unzip: {}", e),
-            EpubDeflationError(code) =>
-                write!(f, "Unzipper exited with an error code: {:?}", code),
+            NativeConversionError(message) =>
+                write!(f, "Conversion failed: {}", message),
+            TimeoutError(timeout) =>
+                write!(f, "Conversion timed out after {} seconds", timeout.as_secs()),
+            CancelledError =>
+                write!(f, "Conversion was cancelled"),
+            EpubExtractionError(e) =>
+                write!(f, "Failed to extract epub: {}", e),
+            UnsafeEntryError(name) =>
+                write!(f, "Epub contains an unsafe path: {}", name),
+            InvalidEpubError =>
+                write!(f, "Epub doesn't contain META-INF/container.xml"),
+            CacheWriteError(e) =>
+                write!(f, "Failed to write to the cache dir: {}", e),
             CleanUpError(e) =>
                 write!(f, "Failed to remove temporary epub file: {}", e),
+            NoUsableSourceError(errors) => {
+                try!(write!(f, "No source format could be converted"));
+                for (i, &(ref format, ref e)) in errors.iter().enumerate() {
+                    try!(write!(f, "{} {}: {}", if i == 0 { ":" } else { ";" }, format, e));
+                }
+                Ok(())
+            },
         }
     }
 }
 
-fn convert_to_epub(converter_bin: &str,
-                   srcpath: &PathBuf, dstpath: &PathBuf)
-                   -> Result<(), ConversionError>  {
-    info!("Convert {:?} to epub and extract to {:?}...", srcpath, dstpath);
-    let result = Command::new(converter_bin)
-        .arg(srcpath.to_str().unwrap())
-        .arg(dstpath.to_str().unwrap())
-        .arg("--no-default-epub-cover")
-        .arg("--output-profile")
-        .arg("tablet")
-    //.arg("--extract-to")
-    //.arg(dstpath)
-        //.arg("--title")
-        //.arg(title)
-        .status();
+impl ConversionError {
+    /// Whether the error is due to the book itself, so that retrying is
+    /// pointless until the source changes. Errors of the environment, like a
+    /// missing converter or a full disk, are worth retrying. Books exceeding
+    /// the time limit would most likely exceed it again.
+    pub fn is_permanent(&self) -> bool {
+        match self {
+            EpubConversionError(_) | NativeConversionError(_) | TimeoutError(_) |
+            EpubExtractionError(_) | UnsafeEntryError(_) | InvalidEpubError => true,
+            EpubConversionCommandError(_) | CacheWriteError(_) | CleanUpError(_) |
+            CancelledError => false,
+            NoUsableSourceError(errors) =>
+                !errors.is_empty() && errors.iter().all(|&(_, ref e)| e.is_permanent()),
+        }
+    }
+}
 
-    match result {
-        Ok(code) if code.success() => Ok(()),
-        Ok(code) => Err(EpubConversionError(code)),
-        Err(e) => Err(EpubConversionCommandError(e))
+impl From<ZipError> for ConversionError {
+    fn from(e: ZipError) -> Self {
+        EpubExtractionError(e)
     }
 }
 
-fn convert(converter_bin: &str, src: &str, dest: &str)
-           -> Result<(), ConversionError> {
-    let src: PathBuf = PathBuf::from(src);
-    let dest: PathBuf = PathBuf::from(dest);
+/// Converts an entry name into a relative path, rejecting absolute paths
+/// and parent references that would escape the extraction directory.
+pub fn safe_entry_path(name: &str) -> Option<PathBuf> {
+    let mut path = PathBuf::new();
+    for component in Path::new(name).components() {
+        match component {
+            Component::Normal(c) => path.push(c),
+            Component::CurDir => {},
+            _ => return None
+        }
+    }
+    Some(path)
+}
 
-    if check_cache_availability(&dest) {
-        return Ok(())
+fn extract_archive(epubpath: &Path, dest: &Path, control: &JobControl)
+                   -> Result<(), ConversionError> {
+    let mut archive = try!(ZipArchive::new(
+        try!(File::open(epubpath).map_err(|e| EpubExtractionError(ZipError::Io(e))))));
+    for i in 0..archive.len() {
+        try!(control.check());
+        let mut entry = try!(archive.by_index(i));
+        let relpath = match safe_entry_path(entry.name()) {
+            Some(p) => p,
+            None => return Err(UnsafeEntryError(entry.name().to_string()))
+        };
+        let mut outpath = dest.to_path_buf();
+        outpath.push(&relpath);
+
+        if entry.name().ends_with('/') {
+            try!(fs::create_dir_all(&outpath).map_err(CacheWriteError));
+        } else {
+            if let Some(parent) = outpath.parent() {
+                try!(fs::create_dir_all(parent).map_err(CacheWriteError));
+            }
+            let mut out = try!(File::create(&outpath).map_err(CacheWriteError));
+            try!(io::copy(&mut entry, &mut out).map_err(
+                |e| EpubExtractionError(ZipError::Io(e))));
+        }
     }
+    Ok(())
+}
 
-    let (epubpath, need_cleanup) = if src.to_str().unwrap().ends_with(".epub") {
-        (src.clone(), false)
+/// Extracts `epubpath` into a staging directory next to `dest`, and moves it
+/// to `dest` only once it's complete and looks like an epub, so that readers
+/// never see a partially extracted book.
+fn extract_epub(epubpath: &Path, dest: &Path, source: &SourceFingerprint,
+                control: &JobControl)
+                -> Result<(), ConversionError> {
+    let staging = try!(staging_path(dest, "partial").map_err(CacheWriteError));
+    try!(fs::create_dir_all(&staging).map_err(CacheWriteError));
+
+    let result = extract_archive(epubpath, &staging, control).and_then(|_| {
+        if check_cache_availability(&staging) {
+            source.write_to(&staging).map_err(CacheWriteError)
+        } else {
+            Err(InvalidEpubError)
+        }
+    }).and_then(|_| control.check());
+    if let Err(e) = result {
+        if let Err(rm_err) = fs::remove_dir_all(&staging) {
+            warn!("Failed to remove {:?}: {}", staging, rm_err);
+        }
+        return Err(e);
+    }
+
+    let result = if dest.exists() {
+        // Outdated or broken copy
+        fs::remove_dir_all(dest)
+    } else {
+        Ok(())
+    }.and_then(|_| fs::rename(&staging, dest));
+    if let Err(e) = result {
+        if let Err(rm_err) = fs::remove_dir_all(&staging) {
+            warn!("Failed to remove {:?}: {}", staging, rm_err);
+        }
+        // Another process may have put the same book in place meanwhile.
+        if check_cache_freshness(&dest.to_path_buf(), &[source.clone()]).is_some() {
+            return Ok(());
+        }
+        return Err(CacheWriteError(e));
+    }
+    Ok(())
+}
+
+fn convert_from(converters: &Converters, source: &SourceFingerprint, dest: &Path,
+                options: &ConversionOptions, control: &JobControl)
+                -> Result<(), ConversionError> {
+    let src = &source.path;
+    let format = source.format();
+    let (epubpath, need_cleanup) = if format == "EPUB" {
+        (src.to_path_buf(), false)
     } else {
-        let mut epubpath = dest.clone();
-        epubpath.set_extension("epub");
-        try!(convert_to_epub(converter_bin, &src, &epubpath));
+        let epubpath = try!(staging_path(dest, "epub").map_err(CacheWriteError));
+        let converter = converters.for_format(&format);
+        info!("Convert {:?} to epub with {} and extract to {:?}...",
+              src, converter.name(), dest);
+        control.log(&format!("Converting {:?} with {}", src, converter.name()));
+        // Converters may finish without noticing the job was interrupted.
+        if let Err(e) = converter.convert(src, &epubpath, options, control)
+            .and_then(|_| control.check()) {
+            if epubpath.exists() {
+                try!(fs::remove_file(&epubpath).map_err(CleanUpError));
+            }
+            return Err(e);
+        }
         (epubpath, true)
     };
 
-    let result = Command::new("unzip")
-        .arg("-d").arg(dest)
-        .arg(&epubpath)
-        .status();
-    try!(match result {
-        Ok(code) if code.success() => Ok(()),
-        Ok(code) => Err(EpubDeflationError(code)),
-        Err(e) => Err(EpubDeflationCommandError(e))
-    });
+    control.log(&format!("Extracting {:?}", epubpath));
+    let result = extract_epub(&epubpath, dest, source, control);
 
     if need_cleanup {
-        let result = Command::new("rm")
-            .arg(&epubpath)
-            .status();
-        try!(match result {
-            Ok(_) => Ok(()),
-            Err(e) => Err(CleanUpError(e))
-        });
+        try!(fs::remove_file(&epubpath).map_err(CleanUpError));
     };
 
-    Ok(())
+    result
+}
+
+/// Converts the first source that works, and returns it.
+fn convert(converters: &Converters, sources: &[SourceFingerprint], dest: &Path,
+           options: &ConversionOptions, control: &JobControl)
+           -> Result<SourceFingerprint, ConversionError> {
+    if let Some(source) = check_cache_freshness(&dest.to_path_buf(), sources) {
+        return Ok(source)
+    }
+
+    let mut errors = Vec::new();
+    for source in sources {
+        match convert_from(converters, source, dest, options, control) {
+            Ok(_) => return Ok(source.clone()),
+            // Falling back would be interrupted as well
+            Err(e @ TimeoutError(_)) | Err(e @ CancelledError) => return Err(e),
+            Err(e) => {
+                warn!("Conversion from {:?} failed: {}", source.path, e);
+                control.log(&format!("Conversion from {:?} failed: {}", source.path, e));
+                errors.push((source.format(), e));
+            }
+        }
+    }
+    Err(NoUsableSourceError(errors))
+}
+
+/// Everything a worker needs, cloned for each (re)started worker thread.
+#[derive(Clone)]
+pub struct WorkerContext {
+    pub converters: Arc<Converters>,
+    pub queue: TaskQueue,
+    pub jobs: JobRegistry,
+    pub cache: CacheManager,
+    pub store: Store,
+    /// Wall-clock limit of each job
+    pub timeout: Option<Duration>,
+    /// Whether to evict old books after each conversion
+    pub collect_garbage: bool,
+}
+
+/// Health of a worker as reported to the HTTP side.
+#[derive(Clone, Debug, Serialize)]
+pub struct WorkerStatus {
+    pub id: usize,
+    /// Book being converted, if any
+    pub bookid: Option<i64>,
+    /// Generation of the job being run
+    #[serde(skip)]
+    pub generation: Option<u64>,
+    /// Times the worker was restarted after a panic
+    pub restarts: u64,
+    pub last_panic: Option<String>,
+    /// Seconds since the epoch
+    pub last_panic_at: Option<u64>,
+}
+
+/// Status of all workers, shared between the workers, their supervisors
+/// and the HTTP handlers.
+#[derive(Clone)]
+pub struct WorkerHealth {
+    workers: Arc<Mutex<Vec<WorkerStatus>>>,
+}
+
+impl WorkerHealth {
+    fn new(count: usize) -> Self {
+        WorkerHealth {
+            workers: Arc::new(Mutex::new((0..count).map(|id| WorkerStatus {
+                id: id,
+                bookid: None,
+                generation: None,
+                restarts: 0,
+                last_panic: None,
+                last_panic_at: None,
+            }).collect()))
+        }
+    }
+
+    pub fn snapshot(&self) -> Vec<WorkerStatus> {
+        self.workers.lock().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    fn update<F>(&self, id: usize, f: F) where F: FnOnce(&mut WorkerStatus) {
+        if let Some(status) = self.workers.lock().unwrap_or_else(PoisonError::into_inner).get_mut(id) {
+            f(status);
+        }
+    }
+}
+
+fn panic_message(panic: &Box<Any + Send>) -> String {
+    if let Some(s) = panic.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = panic.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+fn process_task(ctx: &WorkerContext, task: ConversionTask) {
+    run_task(ctx, &task);
+    if let Err(e) = ctx.store.remove_task(task.bookid, task.generation) {
+        warn!("Failed to remove the finished task of book {} from the store: {}",
+              task.bookid, e);
+    }
+}
+
+fn run_task(ctx: &WorkerContext, task: &ConversionTask) {
+    let cancel = match ctx.jobs.running(task.bookid, task.generation) {
+        Some(cancel) => cancel,
+        None => {
+            debug!("Skipping the cancelled task for book {}", task.bookid);
+            return;
+        }
+    };
+    let log_path = ctx.cache.log_path(task.bookid);
+    let log = match ConversionLog::create(&log_path) {
+        Ok(log) => Some(log),
+        Err(e) => {
+            warn!("Failed to create {:?}: {}", log_path, e);
+            None
+        }
+    };
+    let control = JobControl {
+        bookid: task.bookid,
+        generation: task.generation,
+        started_at: Instant::now(),
+        timeout: ctx.timeout,
+        cancel: cancel,
+        jobs: ctx.jobs.clone(),
+        log: log,
+    };
+    let result = convert(&ctx.converters, &task.sources, &task.dest, &task.options,
+                         &control);
+    control.log(&match result {
+        Ok(ref source) => format!("Converted from {:?}", source.path),
+        Err(ref e) => format!("Failed: {}", e),
+    });
+    match result {
+        Ok(source) => {
+            if let Err(e) = ctx.store.clear_failure(task.bookid) {
+                warn!("Failed to clear the conversion failure of book {}: {}",
+                      task.bookid, e);
+            }
+            ctx.cache.touch(task.bookid);
+            // Evict before telling clients the book is ready, and never the
+            // book itself, or it'd be converted again on the next request.
+            if ctx.collect_garbage {
+                if let Err(e) = ctx.cache.collect_garbage(false, Some(&task.dest)) {
+                    warn!("Cache eviction failed: {}", e);
+                }
+            }
+            ctx.jobs.succeeded(task.bookid, task.generation, source.format());
+        },
+        Err(CancelledError) => {
+            info!("Conversion of book {} was cancelled", task.bookid);
+            ctx.jobs.cancelled(task.bookid, task.generation);
+        },
+        Err(e) => {
+            warn!("Convertion failed: {}", e);
+            if e.is_permanent() {
+                if let Err(e) = ctx.store.record_failure(task.bookid, &task.sources,
+                                                         &e.to_string()) {
+                    warn!("Failed to record the conversion failure of book {}: {}",
+                          task.bookid, e);
+                }
+            }
+            ctx.jobs.failed(task.bookid, task.generation, e.to_string());
+        }
+    }
+}
+
+fn worker_loop(ctx: WorkerContext, health: WorkerHealth, id: usize) {
+    loop {
+        let task = ctx.queue.pop();
+        let bookid = task.bookid;
+        let generation = task.generation;
+        health.update(id, |status| {
+            status.bookid = Some(bookid);
+            status.generation = Some(generation);
+        });
+        process_task(&ctx, task);
+        health.update(id, |status| {
+            status.bookid = None;
+            status.generation = None;
+        });
+    }
 }
 
-pub fn worker_loop(converter_bin: &str,
-               task_rx: Receiver<(PathBuf, PathBuf)>) {
+/// Runs a worker, and restarts it whenever it panics.
+/// The job the worker was running is marked as failed, since whatever made
+/// it panic would most likely do so again.
+///
+/// Restarts are delayed, doubling the delay while the worker keeps
+/// panicking right away, so that a persistent failure doesn't spin.
+fn supervise(ctx: WorkerContext, health: WorkerHealth, id: usize) {
+    let mut delay = Duration::from_millis(MIN_RESTART_DELAY_MS);
     loop {
-        let (src, dest) = task_rx.recv().unwrap();
+        let worker_ctx = ctx.clone();
+        let worker_health = health.clone();
+        let started_at = Instant::now();
+        let result = panic::catch_unwind(AssertUnwindSafe(
+            || worker_loop(worker_ctx, worker_health, id)));
+        let panic = match result {
+            Ok(()) => return,
+            Err(panic) => panic_message(&panic)
+        };
+        if started_at.elapsed() >= Duration::from_millis(MAX_RESTART_DELAY_MS) {
+            delay = Duration::from_millis(MIN_RESTART_DELAY_MS);
+        }
+        error!("Worker {} panicked: {}", id, panic);
+
+        let mut bookid = None;
+        let mut generation = None;
+        health.update(id, |status| {
+            bookid = status.bookid.take();
+            generation = status.generation.take();
+            status.restarts += 1;
+            status.last_panic = Some(panic.clone());
+            status.last_panic_at = Some(now());
+        });
+        if let (Some(bookid), Some(generation)) = (bookid, generation) {
+            ctx.jobs.failed(bookid, generation, format!("Converter crashed: {}", panic));
+            // Otherwise it would crash the worker again after a restart
+            if let Err(e) = ctx.store.remove_task(bookid, generation) {
+                warn!("Failed to remove the task of book {} from the store: {}",
+                      bookid, e);
+            }
+        }
+
+        info!("Restarting worker {} in {:?}", id, delay);
+        thread::sleep(delay);
+        delay = (delay * 2).min(Duration::from_millis(MAX_RESTART_DELAY_MS));
+    }
+}
 
-        let result = convert(converter_bin,
-                             src.to_str().unwrap(),
-                             dest.to_str().unwrap());
-        match result {
-            Ok(_) => {},
+/// Puts the tasks left in the store by a previous run back into the queue.
+/// Sources and options are looked up again, since the library may have
+/// changed while the server was down.
+pub fn resume_tasks(ctx: &WorkerContext, conn: &Connection, data_path: &Path) {
+    let tasks = match ctx.store.pending_tasks() {
+        Ok(tasks) => tasks,
+        Err(e) => {
+            warn!("Failed to load pending tasks: {}", e);
+            return;
+        }
+    };
+    if !tasks.is_empty() {
+        info!("Resuming {} pending conversion task(s)", tasks.len());
+    }
+    for mut task in tasks {
+        let bookid = task.bookid;
+        let stored_generation = task.generation;
+        task.sources = find_sources(conn, data_path, bookid);
+        if task.sources.is_empty()
+            || check_cache_freshness(&task.dest, &task.sources).is_some() {
+            debug!("Dropping the pending task of book {}; nothing to convert", bookid);
+            if let Err(e) = ctx.store.remove_task(bookid, stored_generation) {
+                warn!("Failed to remove the task of book {} from the store: {}", bookid, e);
+            }
+            continue;
+        }
+        // It'd fail again; the task may have been saved before the failure
+        // was recorded, e.g. when the server stopped right in between.
+        match ctx.store.get_failure(bookid, &task.sources) {
+            Ok(Some(_)) => {
+                debug!("Dropping the pending task of book {}; known to fail conversion",
+                       bookid);
+                if let Err(e) = ctx.store.remove_task(bookid, stored_generation) {
+                    warn!("Failed to remove the task of book {} from the store: {}",
+                          bookid, e);
+                }
+                continue;
+            },
+            Ok(None) => {},
+            Err(e) => warn!("Failed to look up the conversion failure of book {}: {}",
+                            bookid, e)
+        }
+        task.options = BookList::new(conn).conversion_options(bookid);
+        task.generation = match ctx.jobs.try_queue(bookid) {
+            Some(generation) => generation,
+            None => continue
+        };
+        let queued = task.clone();
+        if ctx.queue.try_push(task).is_err() {
+            // The task stays in the store to be resumed on the next start.
+            warn!("Queue is full; postponing the pending task of book {}", bookid);
+            ctx.jobs.forget(bookid);
+            continue;
+        }
+        // Generations restart from zero, so the stored one is renewed.
+        if let Err(e) = ctx.store.save_task(&queued) {
+            warn!("Failed to persist the task of book {}: {}", bookid, e);
+        }
+    }
+}
+
+/// Outcome of `convert_all`
+#[derive(Debug, Default)]
+pub struct BatchReport {
+    pub succeeded: usize,
+    pub failed: usize,
+    /// Tasks left unconverted because the cache was full
+    pub skipped: usize,
+}
+
+/// Converts everything in `ctx.queue` with `parallelism` threads, printing
+/// progress to stdout, and returns once the queue is drained or the cache
+/// is full.
+///
+/// Evicting books during the batch would only make room by dropping the
+/// ones it has just converted, so `ctx.collect_garbage` should be off.
+pub fn convert_all(ctx: WorkerContext, parallelism: usize) -> BatchReport {
+    let total = ctx.queue.len();
+    let done = Arc::new(AtomicUsize::new(0));
+    let report = Arc::new(Mutex::new(BatchReport::default()));
+    let used = match ctx.cache.limit() {
+        Some(_) => match ctx.cache.usage() {
+            Ok(used) => Some(used),
             Err(e) => {
-                warn!("Convertion failed: {}", e)
+                warn!("Failed to measure the cache; ignoring its limit: {}", e);
+                None
             }
+        },
+        None => None
+    };
+    let used = Arc::new(Mutex::new(used));
+
+    let threads: Vec<_> = (0..parallelism.max(1)).map(|id| {
+        let ctx = ctx.clone();
+        let done = done.clone();
+        let report = report.clone();
+        let used = used.clone();
+        thread::Builder::new()
+            .name(format!("batch-{}", id))
+            .spawn(move || {
+                loop {
+                    let full = match (*used.lock().unwrap(), ctx.cache.limit()) {
+                        (Some(used), Some(limit)) => used >= limit,
+                        _ => false
+                    };
+                    if full {
+                        break;
+                    }
+                    let task = match ctx.queue.try_pop() {
+                        Some(task) => task,
+                        None => break
+                    };
+                    let bookid = task.bookid;
+                    let generation = task.generation;
+                    let dest = task.dest.clone();
+                    let result = panic::catch_unwind(AssertUnwindSafe(
+                        || process_task(&ctx, task)));
+                    if let Err(panic) = result {
+                        error!("Conversion of book {} panicked: {}",
+                               bookid, panic_message(&panic));
+                        ctx.jobs.failed(bookid, generation,
+                                        "Converter crashed".to_string());
+                        // Otherwise it would crash again when the tasks are resumed
+                        if let Err(e) = ctx.store.remove_task(bookid, generation) {
+                            warn!("Failed to remove the task of book {} from the store: {}",
+                                  bookid, e);
+                        }
+                    }
+
+                    let state = ctx.jobs.get(bookid).map(|job| job.state);
+                    if state == Some(JobState::Succeeded) {
+                        if let Some(ref mut used) = *used.lock().unwrap() {
+                            *used += dir_size(&dest).unwrap_or(0);
+                        }
+                    }
+                    let n = done.fetch_add(1, AtomicOrdering::SeqCst) + 1;
+                    println!("[{}/{}] Book {}: {}", n, total, bookid,
+                             state.unwrap_or(JobState::Failed).name());
+                    let mut report = report.lock().unwrap();
+                    if state == Some(JobState::Succeeded) {
+                        report.succeeded += 1;
+                    } else {
+                        report.failed += 1;
+                    }
+                }
+            })
+            .expect("Failed to start a batch conversion thread")
+    }).collect();
+
+    for handle in threads {
+        if handle.join().is_err() {
+            error!("A batch conversion thread died");
+        }
+    }
+    let skipped = ctx.queue.len();
+    if skipped > 0 {
+        println!("The cache is full; skipped {} book(s)", skipped);
+    }
+    let report = report.lock().unwrap();
+    BatchReport {
+        succeeded: report.succeeded,
+        failed: report.failed,
+        skipped: skipped,
+    }
+}
+
+/// Starts `count` supervised workers taking tasks from `ctx.queue`.
+pub fn spawn_workers(count: usize, ctx: WorkerContext) -> WorkerHealth {
+    let health = WorkerHealth::new(count);
+    for id in 0..count {
+        let worker_ctx = ctx.clone();
+        let worker_health = health.clone();
+        thread::Builder::new()
+            .name(format!("worker-{}", id))
+            .spawn(move || supervise(worker_ctx, worker_health, id))
+            .expect("Failed to start a worker thread");
+    }
+    health
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{safe_entry_path, ConversionTask, Priority, TaskQueue};
+    use db::ConversionOptions;
+    use std::path::PathBuf;
+
+    fn task(bookid: i64, priority: Priority, generation: u64) -> ConversionTask {
+        ConversionTask {
+            bookid: bookid,
+            dest: PathBuf::from(format!("/cache/{}", bookid)),
+            sources: Vec::new(),
+            options: ConversionOptions::new(),
+            priority: priority,
+            generation: generation,
+        }
+    }
+
+    fn drain(queue: &TaskQueue) -> Vec<i64> {
+        let mut bookids = Vec::new();
+        while let Some(task) = queue.try_pop() {
+            bookids.push(task.bookid);
         }
+        bookids
+    }
+
+    #[test]
+    fn entry_paths() {
+        assert_eq!(safe_entry_path("OEBPS/text.xhtml"),
+                   Some(PathBuf::from("OEBPS/text.xhtml")));
+        assert_eq!(safe_entry_path("./META-INF/container.xml"),
+                   Some(PathBuf::from("META-INF/container.xml")));
+        assert_eq!(safe_entry_path("OEBPS/"), Some(PathBuf::from("OEBPS")));
+        assert_eq!(safe_entry_path("../evil"), None);
+        assert_eq!(safe_entry_path("OEBPS/../../evil"), None);
+        assert_eq!(safe_entry_path("/etc/passwd"), None);
+    }
+
+    #[test]
+    fn queue_priority() {
+        let queue = TaskQueue::new(4);
+        queue.try_push(task(1, Priority::Background, 0)).ok().unwrap();
+        queue.try_push(task(2, Priority::Interactive, 1)).ok().unwrap();
+        queue.try_push(task(3, Priority::Background, 2)).ok().unwrap();
+        queue.try_push(task(4, Priority::Interactive, 3)).ok().unwrap();
+        // Full
+        let rejected = queue.try_push(task(5, Priority::Interactive, 4)).err().unwrap();
+        assert_eq!(rejected.bookid, 5);
+        assert_eq!(queue.len(), 4);
+        // Interactive ones first, each in arrival order
+        assert_eq!(drain(&queue), vec![2, 4, 1, 3]);
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[test]
+    fn queue_promote() {
+        let queue = TaskQueue::new(4);
+        queue.try_push(task(1, Priority::Background, 0)).ok().unwrap();
+        queue.try_push(task(2, Priority::Background, 1)).ok().unwrap();
+        queue.try_push(task(3, Priority::Interactive, 2)).ok().unwrap();
+        queue.promote(2, Priority::Interactive);
+        // Never lowered
+        queue.promote(3, Priority::Background);
+        assert_eq!(drain(&queue), vec![2, 3, 1]);
+    }
 
+    #[test]
+    fn queue_remove_by_generation() {
+        let queue = TaskQueue::new(4);
+        queue.try_push(task(1, Priority::Background, 0)).ok().unwrap();
+        queue.try_push(task(2, Priority::Background, 1)).ok().unwrap();
+        // A later job of the book
+        assert!(!queue.remove(1, 2));
+        assert!(queue.remove(1, 0));
+        assert!(!queue.remove(1, 0));
+        assert_eq!(drain(&queue), vec![2]);
     }
 }
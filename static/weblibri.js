@@ -11,8 +11,15 @@ function pollConversion() {
         url: API_ROOT + "/" + bookid + "/reader_status.js?enqueue=0",
         success: function(stat) {
             if (stat.is_ready) {
-                window.location.href = APP_PREFIX + "/reader/" + bookid;
+                window.location.href = stat.uri;
+            } else if (stat.failure !== null) {
+                showPreviewUnavailable(stat.failure.error);
+            } else if (stat.job !== null && stat.job.state === "failed") {
+                showConversionError(stat.job.error);
+            } else if (stat.job !== null && stat.job.state === "cancelled") {
+                showConversionError("Conversion was cancelled");
             } else {
+                showConversionProgress(stat.job);
                 $("#convertModal").data("next-poll", nextPoll);
                 setTimeout(pollConversion, nextPoll);
             }
@@ -21,6 +28,77 @@ function pollConversion() {
 }
 
 
+function showConversionProgress(job) {
+    if (job === null || job.progress === null) {
+        return;
+    }
+    $("#bar-spinner").hide();
+    $("#convert-progress").show();
+    $("#convert-progress .progress-bar").css("width", job.progress + "%");
+}
+
+
+function showConversionError(message) {
+    $("#bar-spinner").hide();
+    $("#convert-progress").hide();
+    $("#convert-error").text("Failed to generate a browser preview: " + message);
+    $("#convert-error").show();
+}
+
+
+function showPreviewUnavailable(message) {
+    $("#bar-spinner").hide();
+    $("#convert-progress").hide();
+    $("#convert-error").text("This book cannot be previewed: " + message);
+    $("#convert-error").show();
+}
+
+
+function startPolling(initDelay) {
+    var handle = setTimeout(pollConversion, initDelay);
+    $("#convertModal").data("timeout-handle", handle)
+}
+
+
+/** Follows the conversion job over Server-Sent Events, falling back to
+ * polling if they're unavailable */
+function watchConversion(bookid, readerUri, initDelay) {
+    if (typeof EventSource === "undefined") {
+        startPolling(initDelay);
+        return;
+    }
+    var source = new EventSource(API_ROOT + "/" + bookid + "/events");
+    var finished = false;
+    $("#convertModal").data("event-source", source);
+
+    source.addEventListener("progress", function(e) {
+        showConversionProgress(JSON.parse(e.data));
+    });
+    source.addEventListener("succeeded", function(e) {
+        finished = true;
+        source.close();
+        window.location.href = readerUri;
+    });
+    source.addEventListener("failed", function(e) {
+        finished = true;
+        source.close();
+        showConversionError(JSON.parse(e.data).error);
+    });
+    source.addEventListener("cancelled", function(e) {
+        finished = true;
+        source.close();
+        showConversionError("Conversion was cancelled");
+    });
+    source.onerror = function() {
+        // e.g. the job was gone, or the connection was lost
+        source.close();
+        if (!finished) {
+            startPolling(initDelay);
+        }
+    };
+}
+
+
 function openReader(bookid) {
     var initDelay = 1000;
     $.ajax({
@@ -28,78 +106,161 @@ function openReader(bookid) {
         url: API_ROOT + "/" + bookid + "/reader_status.js",
         success: function(stat) {
             if (stat.is_ready) {
-                window.location.href = APP_PREFIX + "/reader/" + bookid;
+                window.location.href = stat.uri;
+            } else if (stat.failure !== null) {
+                $("#convertModal").modal();
+                showPreviewUnavailable(stat.failure.error);
             } else {
+                $("#bar-spinner").show();
+                $("#convert-progress").hide();
+                $("#convert-error").hide();
                 $("#convertModal").data("waiting", bookid)
                 $("#convertModal").data("next-poll", initDelay)
                 $("#convertModal").on('hide.bs.modal', function (e) {
                     clearTimeout($("#convertModal").data("timeout-handle"));
+                    var source = $("#convertModal").data("event-source");
+                    if (source) {
+                        source.close();
+                    }
                 });
                 $("#convertModal").modal();
-                var handle = setTimeout(pollConversion, initDelay);
-                $("#convertModal").data("timeout-handle", handle)
+                watchConversion(bookid, stat.uri, initDelay);
+            }
+        },
+        error: function(xhr) {
+            if (xhr.status === 503) {
+                $("#convertModal").modal();
+                showConversionError("The server is busy. Please try again later.");
             }
         }
     });
 }
 
+/** Escapes text to be put in HTML, including attribute values */
+function escapeHtml(text) {
+    return String(text)
+        .replace(/&/g, "&amp;")
+        .replace(/</g, "&lt;")
+        .replace(/>/g, "&gt;")
+        .replace(/"/g, "&quot;")
+        .replace(/'/g, "&#39;");
+}
+
 function genBookItemTableRow(data) {
     var datalinks = "";
     for (var i = 0; i < data.available_data.length; ++ i) {
         var ext = data.available_data[i];
-        var link = APP_PREFIX + "/data/" + data.id + "/" + ext;
-        datalinks += "<a href=\"" + link + "\">" + ext + "</a> ";
+        var link = APP_PREFIX + "/data/" + data.id + "/" + encodeURIComponent(ext);
+        datalinks += "<a href=\"" + escapeHtml(link) + "\">" + escapeHtml(ext) + "</a> ";
+    }
+    var series = "";
+    if (data.series !== null) {
+        series = escapeHtml(data.series + " [" + data.series_index + "]");
+    }
+    var cover = "";
+    if (data.thumbnail !== null) {
+        cover = "<img class=\"thumbnail-small\" src=\"" + escapeHtml(data.thumbnail) + "\">";
     }
-    return (
-        "<tr>"
-            + "<td><a onclick=\"openReader(" + data.id
+    return [
+        cover,
+        "<a onclick=\"openReader(" + Number(data.id)
             + ")\" href=\"javascript:void(0);\">"
             + "<span class=\"glyphicon glyphicon-book\" aria-hidden=\"true\"></span>"
-            + "</a></td>"
-            + "<td>" + data.title + "</td>"
-            + "<td>" + data.author_sort + "</td>"
-            + "<td>" + datalinks + "</td>"
-            + "</tr>");
+            + "</a>",
+        "<a href=\"" + APP_PREFIX + "/details/" + Number(data.id) + "\">"
+            + escapeHtml(data.title) + "</a>",
+        escapeHtml(data.author_sort),
+        series,
+        escapeHtml(data.tags.join(", ")),
+        datalinks
+    ];
+}
+
+/** Shows the error of the search query next to the search box, or hides it */
+function showSearchError(message) {
+    var errorElem = $("#search-error");
+    if (errorElem.length === 0) {
+        errorElem = $("<div id=\"search-error\" class=\"text-danger\"></div>");
+        $("#booklist .dataTables_filter").after(errorElem);
+    }
+    if (message) {
+        errorElem.text(message).show();
+    } else {
+        errorElem.hide();
+    }
+}
+
+/** Translates a DataTables server-side request into a booklist API call */
+function fetchBookList(dtReq, callback, settings) {
+    var params = {
+        offset: dtReq.start,
+        limit: dtReq.length,
+        filter: dtReq.search.value,
+        draw: dtReq.draw
+    };
+    if (dtReq.order.length > 0) {
+        params.sort = dtReq.columns[dtReq.order[0].column].name;
+        params.order = dtReq.order[0].dir;
+    }
+    $.ajax({
+        dataType: "json",
+        url: API_ROOT + "/booklist.js",
+        data: params,
+        success: function(res) {
+            showSearchError(null);
+            callback({
+                draw: res.draw,
+                recordsTotal: res.total,
+                recordsFiltered: res.filtered,
+                data: res.books.map(genBookItemTableRow)
+            });
+        },
+        error: function(xhr) {
+            // e.g. syntax errors in the search query. Passing the error to
+            // DataTables would make it pop up an alert on every keystroke.
+            showSearchError(xhr.responseText || "Failed to load the book list");
+            callback({
+                draw: dtReq.draw,
+                recordsTotal: 0,
+                recordsFiltered: 0,
+                data: []
+            });
+        }
+    });
 }
 
-function renderBookList(data) {
+function renderBookList() {
     var listElem = $("#booklist");
 
     var innerHtml = "<table>";
-    innerHtml += "<thead><tr><th class=\"col_reader_links\"></th><th class=\"col_title\">Title</th><th class=\"col_author_sort\">Author(s)</th><th class=\"col_data_links\">Data</th></tr></thead>";
-    innerHtml += "<tbody>";
-    for (var i = 0; i < data.length; ++ i) {
-        innerHtml += genBookItemTableRow(data[i]);
-    }
-    innerHtml += "</tbody></table>";
+    innerHtml += "<thead><tr><th class=\"col_cover\"></th><th class=\"col_reader_links\"></th><th class=\"col_title\">Title</th><th class=\"col_author_sort\">Author(s)</th><th class=\"col_series\">Series</th><th class=\"col_tags\">Tags</th><th class=\"col_data_links\">Data</th></tr></thead>";
+    innerHtml += "</table>";
     listElem.html(innerHtml);
 
     var table = $("#booklist table").DataTable({
-        "paging": false,
-        //"scrollY": "500px",
-        "scrollCollapse": true,
-        "order": [[1, 'asc']],
+        "serverSide": true,
+        "ajax": fetchBookList,
+        "pageLength": 50,
+        "searchDelay": 500,
+        "order": [[2, 'asc']],
         "dom": 'Rlfrtip',
-        "columnDefs":[
-            {
-                "targets": 0,
-                "width": "20px",
-                "searchable": false,
-                "orderable": false
-            }
+        "columns": [
+            {"name": "", "width": "48px", "searchable": false,
+             "orderable": false},
+            {"name": "", "width": "20px", "searchable": false,
+             "orderable": false},
+            {"name": "title"},
+            {"name": "author_sort"},
+            {"name": "series"},
+            {"name": "", "orderable": false},
+            {"name": "", "orderable": false}
         ]
     });
 
 }
 
 function onReadyMainPage() {
-    $.ajax({
-        dataType: "json",
-        url: API_ROOT + "/booklist.js",
-        success: function(data) {
-            renderBookList(data)
-        }
-    });
+    renderBookList();
 }
 
 
@@ -120,3 +281,169 @@ function onReadyReaderPage() {
         });
     }
 }
+
+
+/** on-ready function for PDF reader page */
+function onReadyPdfReaderPage() {
+    if (document.readyState != "complete") {
+        return;
+    }
+    pdfjsLib.GlobalWorkerOptions.workerSrc = PDF_WORKER_URI;
+    var state = {doc: null, page: 1, scale: 1.5, rendering: false, pending: null};
+    var canvas = document.getElementById("pdf-canvas");
+
+    function render(num) {
+        if (state.rendering) {
+            state.pending = num;
+            return;
+        }
+        state.rendering = true;
+        state.doc.getPage(num).then(function(page) {
+            var viewport = page.getViewport(state.scale);
+            canvas.width = viewport.width;
+            canvas.height = viewport.height;
+            return page.render({
+                canvasContext: canvas.getContext("2d"),
+                viewport: viewport
+            }).promise;
+        }).then(function() {
+            state.rendering = false;
+            $("#pdf-page").val(num);
+            window.scrollTo(0, 0);
+            if (state.pending !== null) {
+                var next = state.pending;
+                state.pending = null;
+                render(next);
+            }
+        });
+    }
+
+    function goTo(num) {
+        if (state.doc === null || num < 1 || num > state.doc.numPages) {
+            return;
+        }
+        state.page = num;
+        render(num);
+    }
+
+    $("#pdf-prev").click(function() { goTo(state.page - 1); });
+    $("#pdf-next").click(function() { goTo(state.page + 1); });
+    $("#pdf-page").change(function() { goTo(parseInt($(this).val(), 10)); });
+    $("#pdf-zoom-in").click(function() { state.scale *= 1.25; render(state.page); });
+    $("#pdf-zoom-out").click(function() { state.scale /= 1.25; render(state.page); });
+    $(document).keydown(function(e) {
+        if (e.target.tagName === "INPUT") {
+            return;
+        }
+        if (e.which === 37) {
+            goTo(state.page - 1);
+        } else if (e.which === 39) {
+            goTo(state.page + 1);
+        }
+    });
+
+    // Only the pages being viewed are fetched, with range requests.
+    pdfjsLib.getDocument({
+        url: PDF_URI,
+        disableAutoFetch: true,
+        disableStream: true
+    }).promise.then(function(doc) {
+        state.doc = doc;
+        $("#loader").hide();
+        $("#pdf-pages").text(doc.numPages);
+        $("#pdf-page").attr("max", doc.numPages);
+        render(1);
+    });
+}
+
+
+/** on-ready function for comic reader page */
+function onReadyComicReaderPage() {
+    if (document.readyState != "complete") {
+        return;
+    }
+    var storageKey = "comic-" + BOOK_ID;
+    var saved = JSON.parse(localStorage.getItem(storageKey) || "{}");
+    var state = {
+        pages: [],
+        page: saved.page || 0,
+        rtl: (saved.rtl !== undefined) ? saved.rtl : COMIC_RTL
+    };
+    // Pages are downscaled on the server to what the screen can show.
+    var width = Math.ceil($(window).width() * (window.devicePixelRatio || 1));
+
+    function pageUri(n) {
+        return APP_PREFIX + "/comic/" + BOOK_ID + "/page/" + n + "?width=" + width;
+    }
+
+    function save() {
+        localStorage.setItem(storageKey, JSON.stringify({
+            page: state.page, rtl: state.rtl
+        }));
+    }
+
+    function showDirection() {
+        $("#comic-direction").text(state.rtl ? "Right to left" : "Left to right");
+    }
+
+    function goTo(n) {
+        if (n < 0 || n >= state.pages.length) {
+            return;
+        }
+        state.page = n;
+        $("#comic-page").attr("src", pageUri(n));
+        $("#comic-page-number").val(n + 1);
+        window.scrollTo(0, 0);
+        save();
+        if (n + 1 < state.pages.length) {
+            // Prefetch so that turning the page is instant
+            new Image().src = pageUri(n + 1);
+        }
+    }
+
+    /** Turns the page towards the left or the right of the screen */
+    function turn(toRight) {
+        goTo(state.page + (toRight !== state.rtl ? 1 : -1));
+    }
+
+    $("#comic-left").click(function() { turn(false); });
+    $("#comic-right").click(function() { turn(true); });
+    $("#comic-page").click(function(e) {
+        turn(e.offsetX > $(this).width() / 2);
+    });
+    $("#comic-page-number").change(function() {
+        goTo(parseInt($(this).val(), 10) - 1);
+    });
+    $("#comic-direction").click(function() {
+        state.rtl = !state.rtl;
+        showDirection();
+        save();
+    });
+    $(document).keydown(function(e) {
+        if (e.target.tagName === "INPUT") {
+            return;
+        }
+        if (e.which === 37) {
+            turn(false);
+        } else if (e.which === 39) {
+            turn(true);
+        }
+    });
+    showDirection();
+
+    $.ajax({
+        dataType: "json",
+        url: API_ROOT + "/" + BOOK_ID + "/comic_pages.js",
+        success: function(res) {
+            state.pages = res.pages;
+            $("#loader").hide();
+            $("#comic-pages").text(res.pages.length);
+            $("#comic-page-number").attr("max", res.pages.length);
+            goTo(Math.min(state.page, res.pages.length - 1));
+        },
+        error: function() {
+            $("#loader").hide();
+            $("#comic-viewer").text("Failed to open this comic.");
+        }
+    });
+}